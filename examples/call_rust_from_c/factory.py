@@ -6,7 +6,7 @@ from pathlib import Path
 
 def cmd_cargo_build(pdir: Path, target_file: str):
     return Cmd(
-        inputs={pdir / "src/", pdir / "Cargo.toml"},
+        inputs={pdir / "src/**/*.rs", pdir / "Cargo.toml"},
         output=pdir / "target" / "release" / target_file,
         cmd=["cargo", "build", "--release"],
         cwd=pdir,
@@ -14,7 +14,14 @@ def cmd_cargo_build(pdir: Path, target_file: str):
 
 
 def cmd_compile_c(src: Path, dst: Path):
-    return Cmd(inputs={src}, output=dst, cmd=["gcc", "-c", src, "-o", dst])
+    # Included headers are read from the depfile
+    depfile = dst.with_suffix(".d")
+    return Cmd(
+        inputs={src},
+        output=dst,
+        depfile=depfile,
+        cmd=["gcc", "-MD", "-MF", depfile, "-c", src, "-o", dst],
+    )
 
 
 def cmd_link(inputs: List[Path], output: Path):
@@ -16,6 +16,9 @@ def step_all(root_dir) -> Step:
     return Step(requires={step_touch_first}, cmd=Assert(expr=True, error_msg=""))
 
 
+DEFAULT = step_all
+
+
 def init(cfg):
     cfg["RANDOM_SEED"] = random.random()
 
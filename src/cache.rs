@@ -0,0 +1,388 @@
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Component, Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use crate::config_file::ExecConfig;
+use crate::remote_cache::HttpBackend;
+use crate::state::{hash_bytes, hash_file, hash_tree, Fingerprint};
+
+/// Storage for cached blobs.
+/// Keys are relative slash-separated paths, like `objects/ab/abcd...`.
+pub trait CacheBackend: fmt::Debug + Send + Sync {
+    /// Human-readable location, for log messages
+    fn describe(&self) -> String;
+
+    /// Read a blob. Returns `None` if it doesn't exist.
+    fn get(&self, key: &str) -> io::Result<Option<Vec<u8>>>;
+
+    /// Write a blob, replacing any previous contents
+    fn put(&self, key: &str, data: &[u8]) -> io::Result<()>;
+
+    /// Does a blob exist
+    fn contains(&self, key: &str) -> io::Result<bool> {
+        Ok(self.get(key)?.is_some())
+    }
+}
+
+/// Blobs stored as files in a local directory
+#[derive(Debug)]
+pub struct FilesystemBackend {
+    dir: PathBuf,
+}
+impl FilesystemBackend {
+    pub fn new(dir: PathBuf) -> Self {
+        Self { dir }
+    }
+}
+impl CacheBackend for FilesystemBackend {
+    fn describe(&self) -> String {
+        format!("{:?}", self.dir)
+    }
+
+    fn get(&self, key: &str) -> io::Result<Option<Vec<u8>>> {
+        match fs::read(self.dir.join(key)) {
+            Ok(data) => Ok(Some(data)),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn put(&self, key: &str, data: &[u8]) -> io::Result<()> {
+        let path = self.dir.join(key);
+        fs::create_dir_all(path.parent().unwrap())?;
+        write_atomic(&path, data, None)
+    }
+
+    fn contains(&self, key: &str) -> io::Result<bool> {
+        Ok(self.dir.join(key).exists())
+    }
+}
+
+/// Outputs of a command run, stored in the cache.
+/// Paths inside the root directory are stored relative to it.
+#[derive(Debug, Serialize, Deserialize)]
+struct Entry {
+    /// Hashes of inputs read from the depfile when the entry was stored.
+    /// These must still match for the entry to be used.
+    discovered: BTreeMap<PathBuf, String>,
+    /// Content hash of each output file
+    outputs: BTreeMap<PathBuf, String>,
+    /// Unix permissions of each output file
+    #[serde(default)]
+    modes: BTreeMap<PathBuf, u32>,
+}
+
+/// Content-addressed cache of command outputs.
+/// File contents are stored once under `objects/`, keyed by their hash,
+/// and `entries/` maps command keys to the output files they produced.
+///
+/// Backends are tried in order when restoring, and all of them are written to
+/// when storing. Errors are reported to the caller, which should fall back to
+/// running the command.
+#[derive(Debug, Clone)]
+pub struct Cache {
+    backends: Vec<Arc<dyn CacheBackend>>,
+    /// Keys and entries don't depend on where this is, so that checkouts can share them
+    root_dir: PathBuf,
+}
+impl Cache {
+    pub fn new(backends: Vec<Arc<dyn CacheBackend>>, root_dir: PathBuf) -> Self {
+        Self { backends, root_dir }
+    }
+
+    /// Cache using the backends configured in `Factory.toml` or the command line, if any
+    pub fn from_config(exec_config: &ExecConfig) -> Option<Self> {
+        let mut backends: Vec<Arc<dyn CacheBackend>> = Vec::new();
+        if let Some(dir) = &exec_config.cache_dir {
+            backends.push(Arc::new(FilesystemBackend::new(exec_config.root_dir().join(dir))));
+        }
+        if let Some(url) = &exec_config.cache_url {
+            match HttpBackend::new(url) {
+                Ok(backend) => backends.push(Arc::new(backend)),
+                Err(e) => log::warn!("Remote cache disabled: {}", e),
+            }
+        }
+        if backends.is_empty() {
+            None
+        } else {
+            Some(Self::new(backends, exec_config.root_dir()))
+        }
+    }
+
+    /// Key for a command, derived from its fingerprint,
+    /// the contents of its declared inputs, and its output paths.
+    /// Paths inside the root directory are made relative to it first.
+    pub fn key(
+        &self, argv: &[String], cwd: &Path, env: &HashMap<String, String>, inputs: &[PathBuf],
+        outputs: &[PathBuf],
+    ) -> String {
+        let argv: Vec<String> = argv.iter().map(|arg| relative_str(&self.root_dir, arg)).collect();
+        let env: HashMap<String, String> =
+            env.iter().map(|(k, v)| (k.clone(), relative_str(&self.root_dir, v))).collect();
+        let fingerprint = Fingerprint::new(&argv, &relative(&self.root_dir, cwd), &env);
+
+        let mut input_hashes = BTreeMap::new();
+        for path in inputs {
+            hash_tree(path, &mut input_hashes);
+        }
+        let input_hashes: BTreeMap<PathBuf, String> =
+            input_hashes.into_iter().map(|(path, hash)| (relative(&self.root_dir, &path), hash)).collect();
+        let outputs: Vec<PathBuf> = outputs.iter().map(|path| relative(&self.root_dir, path)).collect();
+
+        let data = serde_json::to_vec(&(fingerprint, input_hashes, outputs)).expect("Serialization failed");
+        hash_bytes(&data)
+    }
+
+    fn entry_key(key: &str) -> String {
+        format!("entries/{}.json", key)
+    }
+
+    fn object_key(hash: &str) -> String {
+        format!("objects/{}/{}", &hash[..2], hash)
+    }
+
+    /// Restore the outputs stored under the key from the first backend that has them.
+    /// Entries writing anything but the declared outputs are not used.
+    /// Returns the inputs discovered from the depfile on a hit, and `None` on a miss.
+    pub fn restore(&self, key: &str, outputs: &[PathBuf]) -> io::Result<Option<Vec<PathBuf>>> {
+        let mut result = Ok(None);
+        for (i, backend) in self.backends.iter().enumerate() {
+            match restore_from(backend.as_ref(), key, &self.root_dir, outputs) {
+                Ok(Some((entry, files))) => {
+                    // Populate the backends that missed, usually a local one
+                    for earlier in &self.backends[..i] {
+                        if let Err(e) = store_to(earlier.as_ref(), key, &entry, &files) {
+                            log::warn!("Storing to cache {} failed: {}", earlier.describe(), e);
+                        }
+                    }
+                    return Ok(Some(entry.discovered.keys().map(|path| self.root_dir.join(path)).collect()));
+                },
+                Ok(None) => {},
+                Err(e) => {
+                    log::warn!("Cache {} unavailable: {}", backend.describe(), e);
+                    result = Err(e);
+                },
+            }
+        }
+        result
+    }
+
+    /// Store the outputs under the key in all backends.
+    /// Returns the last error, if storing failed on any of them.
+    pub fn store(&self, key: &str, outputs: &[PathBuf], discovered: &[PathBuf]) -> io::Result<()> {
+        let mut local_hashes = BTreeMap::new();
+        for path in outputs {
+            hash_tree(path, &mut local_hashes);
+        }
+        let mut output_hashes = BTreeMap::new();
+        let mut modes = BTreeMap::new();
+        let mut files = Files::new();
+        for (path, hash) in local_hashes {
+            let stored = relative(&self.root_dir, &path);
+            modes.insert(stored.clone(), fs::metadata(&path)?.permissions().mode());
+            output_hashes.insert(stored.clone(), hash);
+            files.insert(stored, path);
+        }
+        let mut discovered_hashes = BTreeMap::new();
+        for path in discovered {
+            discovered_hashes.insert(relative(&self.root_dir, path), hash_file(path)?);
+        }
+        let entry = Entry {
+            discovered: discovered_hashes,
+            outputs: output_hashes,
+            modes,
+        };
+
+        let mut result = Ok(());
+        for backend in &self.backends {
+            if let Err(e) = store_to(backend.as_ref(), key, &entry, &files) {
+                log::warn!("Storing to cache {} failed: {}", backend.describe(), e);
+                result = Err(e);
+            }
+        }
+        result
+    }
+}
+
+/// Local path of each file in an entry, by its path in the entry
+type Files = BTreeMap<PathBuf, PathBuf>;
+
+/// Restore an entry, returning it with the paths the files were written to
+fn restore_from(
+    backend: &dyn CacheBackend, key: &str, root_dir: &Path, outputs: &[PathBuf],
+) -> io::Result<Option<(Entry, Files)>> {
+    let entry: Entry = match backend.get(&Cache::entry_key(key))? {
+        Some(contents) => match serde_json::from_slice(&contents) {
+            Ok(entry) => entry,
+            Err(e) => {
+                log::warn!("Cache entry {} in {} is invalid: {}", key, backend.describe(), e);
+                return Ok(None);
+            },
+        },
+        None => return Ok(None),
+    };
+
+    for (path, hash) in &entry.discovered {
+        if hash_file(&root_dir.join(path)).ok().as_ref() != Some(hash) {
+            log::debug!("Cache entry {} not used, {:?} changed", key, path);
+            return Ok(None);
+        }
+    }
+
+    // Entries may come from a shared cache, so they are not trusted to write anywhere else
+    let mut files = Files::new();
+    for (path, hash) in &entry.outputs {
+        if !valid_hash(hash) {
+            log::warn!("Cache entry {} in {} has an invalid hash {:?}", key, backend.describe(), hash);
+            return Ok(None);
+        }
+        match output_path(root_dir, outputs, path) {
+            Some(local) => files.insert(path.clone(), local),
+            None => {
+                log::warn!("Cache entry {} in {} has undeclared output {:?}", key, backend.describe(), path);
+                return Ok(None);
+            },
+        };
+    }
+
+    // Fetch and verify everything before writing anything, to avoid partial restores
+    let mut objects = Vec::new();
+    for (path, hash) in &entry.outputs {
+        match backend.get(&Cache::object_key(hash))? {
+            Some(data) if hash_bytes(&data) == *hash => objects.push((path, data)),
+            Some(_) => {
+                log::warn!("Cache entry {} in {} has a corrupted object {}", key, backend.describe(), hash);
+                return Ok(None);
+            },
+            None => {
+                log::warn!("Cache entry {} in {} is missing objects", key, backend.describe());
+                return Ok(None);
+            },
+        }
+    }
+
+    for (path, data) in objects {
+        let local = &files[path];
+        if let Some(parent) = local.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        // Only permission bits are restored, never setuid and the like
+        let mode = entry.modes.get(path).map(|mode| mode & 0o777);
+        write_atomic(local, &data, mode)?;
+    }
+
+    log::debug!("Cache entry {} restored from {}", key, backend.describe());
+    Ok(Some((entry, files)))
+}
+
+fn store_to(backend: &dyn CacheBackend, key: &str, entry: &Entry, files: &Files) -> io::Result<()> {
+    for (path, hash) in &entry.outputs {
+        let object_key = Cache::object_key(hash);
+        if !backend.contains(&object_key)? {
+            backend.put(&object_key, &fs::read(&files[path])?)?;
+        }
+    }
+    // Entry is written last, so that it never refers to missing objects
+    backend.put(&Cache::entry_key(key), &serde_json::to_vec(entry)?)
+}
+
+/// Object keys are derived from hashes, so anything but a hex SHA-256 digest is rejected
+fn valid_hash(hash: &str) -> bool {
+    hash.len() == 64 && hash.bytes().all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b))
+}
+
+/// Path relative to the root directory, or unchanged if it's outside of it
+fn relative(root_dir: &Path, path: &Path) -> PathBuf {
+    path.strip_prefix(root_dir).unwrap_or(path).to_owned()
+}
+
+/// Argument or environment variable value, with the root directory replaced by `$ROOT`
+/// if the value is a path inside it. Only whole values are rewritten, so that
+/// different commands never get the same key.
+fn relative_str(root_dir: &Path, value: &str) -> String {
+    let root = root_dir.to_string_lossy();
+    match value.strip_prefix(root.trim_end_matches('/')) {
+        Some(rest) if rest.is_empty() || rest.starts_with('/') => format!("$ROOT{}", rest),
+        _ => value.to_owned(),
+    }
+}
+
+/// Local path for a path in an entry, if it is a declared output or a file inside one
+fn output_path(root_dir: &Path, outputs: &[PathBuf], path: &Path) -> Option<PathBuf> {
+    outputs.iter().find_map(|output| {
+        let rest = path.strip_prefix(relative(root_dir, output)).ok()?;
+        if rest.as_os_str().is_empty() {
+            Some(output.clone())
+        } else if rest.components().all(|c| matches!(c, Component::Normal(_))) {
+            Some(output.join(rest))
+        } else {
+            None
+        }
+    })
+}
+
+/// Write through a temporary file, so that readers never see a partial file
+fn write_atomic(path: &Path, data: &[u8], mode: Option<u32>) -> io::Result<()> {
+    let tmp = temporary_path(path);
+    fs::write(&tmp, data)?;
+    if let Some(mode) = mode {
+        fs::set_permissions(&tmp, fs::Permissions::from_mode(mode))?;
+    }
+    fs::rename(tmp, path)
+}
+
+/// Unique name for a temporary file next to the path
+fn temporary_path(path: &Path) -> PathBuf {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(format!(".tmp{}-{}", process::id(), COUNTER.fetch_add(1, Ordering::SeqCst)));
+    path.with_file_name(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn relative_str_inside_root() {
+        let root = Path::new("/home/user/project");
+        assert_eq!(relative_str(root, "/home/user/project"), "$ROOT");
+        assert_eq!(relative_str(root, "/home/user/project/src/main.c"), "$ROOT/src/main.c");
+    }
+
+    #[test]
+    fn relative_str_outside_root() {
+        let root = Path::new("/home/user/project");
+        assert_eq!(relative_str(root, "/mnt/home/user/project/x"), "/mnt/home/user/project/x");
+        assert_eq!(relative_str(root, "/home/user/project2/x"), "/home/user/project2/x");
+        assert_eq!(relative_str(root, "-I/home/user/project/include"), "-I/home/user/project/include");
+        assert_eq!(relative_str(root, "src/main.c"), "src/main.c");
+    }
+
+    #[test]
+    fn hash_format() {
+        assert!(valid_hash(&hash_bytes(b"contents")));
+        assert!(!valid_hash("ab"));
+        assert!(!valid_hash(&"A".repeat(64)));
+        assert!(!valid_hash(&format!("../{}", "a".repeat(61))));
+    }
+
+    #[test]
+    fn output_paths() {
+        let root = Path::new("/project");
+        let outputs = vec![PathBuf::from("/project/out"), PathBuf::from("/project/bin/tool")];
+        let restored = |path: &str| output_path(root, &outputs, Path::new(path));
+        assert_eq!(restored("bin/tool"), Some(PathBuf::from("/project/bin/tool")));
+        assert_eq!(restored("out/a/b"), Some(PathBuf::from("/project/out/a/b")));
+        assert_eq!(restored("out/../escape"), None);
+        assert_eq!(restored("bin/other"), None);
+        assert_eq!(restored("/etc/passwd"), None);
+    }
+}
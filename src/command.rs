@@ -1,25 +1,46 @@
-use pyo3::{prelude::*, types::*};
-use std::collections::HashMap;
+use pyo3::{exceptions, prelude::*, types::*};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
 use std::fs;
-use std::io::{self, Write};
+use std::io::{self, BufRead, BufReader, Read, Write};
 use std::path::{Path, PathBuf};
-use std::time::{Duration, Instant, SystemTime};
+use std::os::unix::process::CommandExt;
+use std::process::{Output, Stdio};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 use super::StepId;
-use crate::config_file::ExecConfig;
+use crate::cache::Cache;
+use crate::cancel;
+use crate::config_file::{ExecConfig, Freshness};
+use crate::console::Console;
+use crate::depfile;
 use crate::envdict::EnvDict;
+use crate::state::{self, Fingerprint, StateDb, StepState};
 
 #[derive(Debug)]
 pub struct CommandResult {
     pub step_id: StepId,
+    /// Wall-clock time when the command was started
+    pub started: SystemTime,
     pub time: Duration,
     pub data: CommandResultData,
+    /// Key and new value for the state database, if it should be updated
+    pub state: Option<(String, StepState)>,
+    /// Earlier failed attempts, if the command was retried
+    pub attempts: Vec<CommandResult>,
+    /// Index of the worker thread that ran the command, if any
+    pub worker: Option<usize>,
 }
 impl CommandResult {
     pub fn success(&self) -> bool {
         match self.data {
             CommandResultData::Fresh => true,
+            CommandResultData::Cached => true,
             CommandResultData::Output(ref out) => out.status.success(),
+            CommandResultData::TimedOut(_) => false,
+            CommandResultData::Cancelled => false,
+            CommandResultData::DryRun => true,
             CommandResultData::Virtual => true,
             CommandResultData::ConditionFalse => true,
         }
@@ -28,7 +49,11 @@ impl CommandResult {
     pub fn fresh(&self) -> bool {
         match self.data {
             CommandResultData::Fresh => true,
+            CommandResultData::Cached => false,
             CommandResultData::Output(_) => false,
+            CommandResultData::TimedOut(_) => false,
+            CommandResultData::Cancelled => false,
+            CommandResultData::DryRun => false,
             CommandResultData::Virtual => false,
             CommandResultData::ConditionFalse => false,
         }
@@ -45,8 +70,16 @@ impl CommandResult {
 pub enum CommandResultData {
     /// Output file was fresh
     Fresh,
+    /// Outputs were restored from the cache
+    Cached,
     /// Command completed
     Output(std::process::Output),
+    /// Command was killed after exceeding its timeout
+    TimedOut(std::process::Output),
+    /// Command was killed because the build was interrupted
+    Cancelled,
+    /// Command would have been executed, but this is a dry run
+    DryRun,
     /// No actual content to run
     Virtual,
     /// Skipped based on the condition parameter
@@ -58,26 +91,127 @@ impl CommandResultData {
         match self {
             Self::Output(out) => {
                 eprintln!("status = {:?}", out.status.code());
-                eprint!("Command stdout:");
-                if out.stdout.is_empty() {
-                    eprintln!(" (empty)");
-                } else {
-                    eprintln!("");
-                    io::stdout().write_all(&out.stdout).unwrap();
-                }
-                eprint!("Command stderr:");
-                if out.stderr.is_empty() {
-                    eprintln!(" (empty)");
-                } else {
-                    eprintln!("");
-                    io::stderr().write_all(&out.stderr).unwrap();
-                }
+                show_output(&out);
+            },
+            Self::TimedOut(out) => {
+                eprintln!("timed out");
+                show_output(&out);
             },
             other => eprintln!("{:?}", other),
         }
     }
 }
 
+/// Why a command has to be executed
+#[derive(Debug, Clone)]
+pub enum RebuildReason {
+    /// Refresh was requested
+    Refresh,
+    /// The command doesn't declare any outputs
+    NoOutputs,
+    /// The command doesn't declare any inputs
+    NoInputs,
+    /// None of the inputs exist
+    InputsMissing,
+    /// The command has not been run successfully before
+    NoPreviousRun,
+    /// An output doesn't exist
+    OutputMissing(PathBuf),
+    /// Command line, working directory or environment changed
+    CommandChanged(Vec<String>),
+    /// An input is newer than the oldest output
+    InputNewer {
+        input: PathBuf,
+        input_time: SystemTime,
+        output: PathBuf,
+        output_time: SystemTime,
+    },
+    /// Contents of an input changed since the last run
+    InputChanged(PathBuf),
+    /// Contents of an output changed since the last run
+    OutputChanged(PathBuf),
+    /// A dependency would be executed, in a dry run
+    DependencyExecuted,
+}
+impl fmt::Display for RebuildReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Refresh => write!(f, "refresh forced"),
+            Self::NoOutputs => write!(f, "no outputs declared"),
+            Self::NoInputs => write!(f, "no inputs declared"),
+            Self::InputsMissing => write!(f, "none of the inputs exist"),
+            Self::NoPreviousRun => write!(f, "no previous run recorded"),
+            Self::OutputMissing(path) => write!(f, "output {:?} missing", path),
+            Self::CommandChanged(changes) => write!(f, "{}", changes.join(", ")),
+            Self::InputNewer {
+                input,
+                input_time,
+                output,
+                output_time,
+            } => write!(
+                f,
+                "input {:?} ({}) is newer than output {:?} ({})",
+                input,
+                format_time(*input_time),
+                output,
+                format_time(*output_time)
+            ),
+            Self::InputChanged(path) => write!(f, "input {:?} changed", path),
+            Self::OutputChanged(path) => write!(f, "output {:?} changed", path),
+            Self::DependencyExecuted => write!(f, "a dependency would be executed"),
+        }
+    }
+}
+
+/// Duration from a number of seconds given in the configuration.
+/// Negative, infinite and NaN values are rejected with a `ValueError`.
+pub(crate) fn duration_from_secs(name: &str, secs: f64) -> PyResult<Duration> {
+    if secs.is_finite() && secs >= 0.0 {
+        Ok(Duration::from_secs_f64(secs))
+    } else {
+        Err(PyErr::new::<exceptions::ValueError, _>(format!(
+            "{} must be a non-negative number of seconds, got {}",
+            name, secs
+        )))
+    }
+}
+
+/// Seconds since the Unix epoch
+fn format_time(t: SystemTime) -> String {
+    match t.duration_since(UNIX_EPOCH) {
+        Ok(d) => format!("{}.{:09}", d.as_secs(), d.subsec_nanos()),
+        Err(_) => format!("{:?}", t),
+    }
+}
+
+/// First path with a different hash, or missing from either side
+fn first_difference(a: &BTreeMap<PathBuf, String>, b: &BTreeMap<PathBuf, String>) -> Option<PathBuf> {
+    a.iter()
+        .find(|(path, hash)| b.get(*path) != Some(hash))
+        .map(|(path, _)| path.clone())
+        .or_else(|| b.keys().find(|path| !a.contains_key(*path)).cloned())
+}
+
+fn show_output(out: &Output) {
+    eprint!("Command stdout:");
+    if out.stdout.is_empty() {
+        eprintln!(" (empty)");
+    } else {
+        eprintln!("");
+        io::stdout().write_all(&out.stdout).unwrap();
+    }
+    eprint!("Command stderr:");
+    if out.stderr.is_empty() {
+        eprintln!(" (empty)");
+    } else {
+        eprintln!("");
+        io::stderr().write_all(&out.stderr).unwrap();
+    }
+}
+
+/// How often running commands are checked for timeouts and cancellation
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
 fn time_modified(p: &Path) -> Option<SystemTime> {
     let mt = p.metadata().ok()?.modified().unwrap();
     if p.is_file() {
@@ -94,68 +228,126 @@ fn time_modified(p: &Path) -> Option<SystemTime> {
 #[derive(Debug)]
 pub struct Command {
     step_id: StepId,
+    name: String,
     cmd: Vec<String>,
     inputs: Option<Vec<PathBuf>>,
-    output: Option<PathBuf>,
+    /// Glob patterns the inputs were expanded from
+    input_globs: Vec<PathBuf>,
+    outputs: Vec<PathBuf>,
     cwd: PathBuf,
     stdout_pass: bool,
     stderr_pass: bool,
+    stream: bool,
+    timeout: Option<Duration>,
+    retries: u32,
+    retry_delay: Duration,
     stdout_file: Option<PathBuf>,
     stderr_file: Option<PathBuf>,
     env: HashMap<String, String>,
+    refresh: bool,
+    explain: bool,
+    freshness: Freshness,
+    depfile: Option<PathBuf>,
+    /// State recorded after the last successful run
+    previous: Option<StepState>,
+    /// Jobserver arguments passed to the process
+    makeflags: Option<String>,
+    cache: Option<Cache>,
 }
 impl Command {
     #[must_use]
-    pub fn run(&self) -> CommandResult {
-        use std::process::Command;
-
+    pub fn run(&self, console: &Console) -> CommandResult {
+        let started = SystemTime::now();
         let start = Instant::now();
 
         log::info!("[step {:>4}] Running: {:?}", self.step_id, self.cmd);
         log::info!("[step {:>4}] Cmd env: {:?}", self.step_id, self.env);
 
-        // Check if outputs are already fresh
-        if let Some(output) = &self.output {
-            if let Some(inputs) = &self.inputs {
-                let output_modified = time_modified(output.as_ref());
-                let inputs_modified = inputs.iter().map(|p| time_modified(p.as_ref())).max().flatten();
-
-                log::trace!(
-                    "[step {:>4}] output [{:?}] ({:?})",
-                    self.step_id,
-                    output_modified,
-                    output
-                );
-                log::trace!(
-                    "[step {:>4}] inputs [{:?}] ({:?})",
-                    self.step_id,
-                    inputs_modified,
-                    inputs
-                );
-
-                if let Some(output_m) = output_modified {
-                    if let Some(inputs_m) = inputs_modified {
-                        if output_m >= inputs_m {
-                            log::info!("[step {:>4}] Fresh", self.step_id);
-                            return CommandResult {
-                                step_id: self.step_id,
-                                time: start.elapsed(),
-                                data: CommandResultData::Fresh,
-                            };
-                        }
-                    }
-                }
+        if cancel::interrupted() {
+            return CommandResult {
+                step_id: self.step_id,
+                started,
+                time: start.elapsed(),
+                data: CommandResultData::Cancelled,
+                state: None,
+                attempts: Vec::new(),
+                worker: None,
+            };
+        }
+
+        if let Some(reason) = self.rebuild_reason() {
+            log::info!("[step {:>4}] Stale: {}", self.step_id, reason);
+            if self.explain {
+                console.println(&format!("[step {} {}] Rebuilding: {}", self.step_id, self.name, reason));
             }
+        } else {
+            log::info!("[step {:>4}] Fresh", self.step_id);
+            return CommandResult {
+                step_id: self.step_id,
+                started,
+                time: start.elapsed(),
+                data: CommandResultData::Fresh,
+                state: None,
+                attempts: Vec::new(),
+                worker: None,
+            };
         }
 
-        let (program, args) = self.cmd.split_first().expect("Empty command");
+        let cache_key = self.cache_key();
+        if let (Some(cache), Some(key)) = (&self.cache, &cache_key) {
+            match cache.restore(key, &self.outputs) {
+                Ok(Some(discovered)) => {
+                    log::info!("[step {:>4}] Restored from cache", self.step_id);
+                    let state = match (self.state_key(), self.current_state(discovered)) {
+                        (Some(key), Some(state)) => Some((key, state)),
+                        _ => None,
+                    };
+                    return CommandResult {
+                        step_id: self.step_id,
+                        started,
+                        time: start.elapsed(),
+                        data: CommandResultData::Cached,
+                        state,
+                        attempts: Vec::new(),
+                        worker: None,
+                    };
+                },
+                Ok(None) => {},
+                Err(e) => log::warn!("[step {:>4}] Cache lookup failed: {}", self.step_id, e),
+            }
+        }
 
-        let output = Command::new(program)
-            .args(args)
-            .envs(&self.env.clone())
-            .current_dir(self.cwd.clone())
-            .output()
-            .expect("failed to execute process");
+        self.execute_and_record(console, started, start, cache_key.as_deref())
+    }
+
+    /// Run the command again after a failed attempt.
+    /// Freshness and cache checks are skipped, as the failed attempt may have written outputs.
+    pub fn retry(&self, console: &Console) -> CommandResult {
+        log::info!("[step {:>4}] Retrying: {:?}", self.step_id, self.cmd);
+        self.execute_and_record(console, SystemTime::now(), Instant::now(), self.cache_key().as_deref())
+    }
+
+    /// Execute the command, and on success store its outputs in the cache and record its state
+    fn execute_and_record(
+        &self, console: &Console, started: SystemTime, start: Instant, cache_key: Option<&str>,
+    ) -> CommandResult {
+        let data = self.execute(console).expect("failed to execute process");
+
+        let output = match &data {
+            CommandResultData::Output(output) | CommandResultData::TimedOut(output) => output,
+            _ => {
+                log::info!("[step {:>4}] Cancelled", self.step_id);
+                return CommandResult {
+                    step_id: self.step_id,
+                    started,
+                    time: start.elapsed(),
+                    data,
+                    state: None,
+                    attempts: Vec::new(),
+                    worker: None,
+                };
+            },
+        };
 
         if self.stdout_pass {
             io::stdout().write_all(&output.stdout).unwrap();
@@ -175,14 +367,389 @@ impl Command {
 
         log::info!("[step {:>4}] Result: {:?}", self.step_id, output.status.code());
 
+        let completed = match &data {
+            CommandResultData::Output(output) => output.status.success(),
+            _ => false,
+        };
+
+        let state = if completed {
+            let discovered = self.read_depfile();
+            if let (Some(cache), Some(key)) = (&self.cache, cache_key) {
+                if let Err(e) = cache.store(key, &self.outputs, &discovered) {
+                    log::warn!("[step {:>4}] Storing outputs in cache failed: {}", self.step_id, e);
+                }
+            }
+            match (self.state_key(), self.current_state(discovered)) {
+                (Some(key), Some(state)) => Some((key, state)),
+                _ => None,
+            }
+        } else {
+            None
+        };
+
+        CommandResult {
+            step_id: self.step_id,
+            started,
+            time: start.elapsed(),
+            data,
+            state,
+            attempts: Vec::new(),
+            worker: None,
+        }
+    }
+
+    /// Print whether the command would be executed, and how, without running it.
+    /// Like `make -n`, it would be if any of its dependencies would be.
+    #[must_use]
+    pub fn dry_run(&self, dependency_executed: bool) -> CommandResult {
+        let started = SystemTime::now();
+        let start = Instant::now();
+
+        let reason = if dependency_executed {
+            Some(RebuildReason::DependencyExecuted)
+        } else {
+            self.rebuild_reason()
+        };
+        let data = if let Some(reason) = reason {
+            println!("[step {:>4}] {}: execute ({})", self.step_id, self.name, reason);
+            println!("    argv: {:?}", self.cmd);
+            println!("    cwd: {:?}", self.cwd);
+            let env: BTreeMap<&String, &String> = self.env.iter().collect();
+            for (key, value) in env {
+                println!("    env: {}={:?}", key, value);
+            }
+            CommandResultData::DryRun
+        } else {
+            println!("[step {:>4}] {}: fresh", self.step_id, self.name);
+            CommandResultData::Fresh
+        };
+
         CommandResult {
             step_id: self.step_id,
+            started,
             time: start.elapsed(),
-            data: CommandResultData::Output(output),
+            data,
+            state: None,
+            attempts: Vec::new(),
+            worker: None,
+        }
+    }
+
+    /// Run the process, killing it on timeout or cancellation.
+    /// Returns `Output`, `TimedOut` or `Cancelled`.
+    fn execute(&self, console: &Console) -> io::Result<CommandResultData> {
+        use std::process::Command;
+
+        let (program, args) = self.cmd.split_first().expect("Empty command");
+
+        let mut command = Command::new(program);
+        command
+            .args(args)
+            .envs(&self.env.clone())
+            .current_dir(self.cwd.clone())
+            // Reading the terminal from a background process group would stop the command
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+
+        if let Some(makeflags) = &self.makeflags {
+            command.env("MAKEFLAGS", makeflags).env("CARGO_MAKEFLAGS", makeflags);
+        }
+
+        // Separate process group, so that the command and its subprocesses
+        // can be killed together, and don't receive terminal signals directly
+        unsafe {
+            command.pre_exec(|| {
+                libc::setpgid(0, 0);
+                Ok(())
+            });
+        }
+
+        let mut child = command.spawn()?;
+
+        let prefix = format!("[step {} {}]", self.step_id, self.name);
+        let console = if self.stream { Some(console.clone()) } else { None };
+        let stdout = capture_lines(child.stdout.take().unwrap(), prefix.clone(), console.clone());
+        let stderr = capture_lines(child.stderr.take().unwrap(), prefix, console);
+
+        let deadline = self.timeout.map(|t| Instant::now() + t);
+        let mut timed_out = false;
+        let status = loop {
+            if let Some(status) = child.try_wait()? {
+                break status;
+            }
+            timed_out = deadline.map(|d| Instant::now() >= d).unwrap_or(false);
+            if timed_out || cancel::interrupted() {
+                cancel::kill_group(&child);
+                break child.wait()?;
+            }
+            thread::sleep(POLL_INTERVAL);
+        };
+
+        let output = Output {
+            status,
+            stdout: stdout.join().unwrap()?,
+            stderr: stderr.join().unwrap()?,
+        };
+
+        Ok(if timed_out {
+            CommandResultData::TimedOut(output)
+        } else if cancel::interrupted() && !status.success() {
+            CommandResultData::Cancelled
+        } else {
+            CommandResultData::Output(output)
+        })
+    }
+
+    /// Check if outputs are already fresh.
+    /// Returns the reason to execute the command if they are not.
+    fn rebuild_reason(&self) -> Option<RebuildReason> {
+        if self.refresh {
+            return Some(RebuildReason::Refresh);
+        }
+
+        if self.outputs.is_empty() {
+            return Some(RebuildReason::NoOutputs);
+        }
+
+        if self.inputs.is_none() && self.depfile.is_none() {
+            return Some(RebuildReason::NoInputs);
+        }
+
+        // Without a recorded run, mtime mode still compares the files, but inputs
+        // from the depfile are unknown and hash mode has nothing to compare against
+        let previous = self.previous.as_ref();
+        let discovered = previous.map(|p| p.discovered.as_slice()).unwrap_or(&[]);
+        let inputs = if let Some(inputs) = self.all_inputs(discovered) {
+            inputs
+        } else if previous.is_none() {
+            return Some(RebuildReason::NoPreviousRun);
+        } else {
+            return Some(RebuildReason::NoInputs);
+        };
+
+        if let Some(missing) = self.outputs.iter().find(|p| !p.exists()) {
+            return Some(RebuildReason::OutputMissing(missing.clone()));
+        }
+
+        if let Some(previous) = previous {
+            let changes = self.fingerprint().changes_since(&previous.fingerprint);
+            if !changes.is_empty() {
+                return Some(RebuildReason::CommandChanged(changes));
+            }
+        }
+
+        match self.freshness {
+            Freshness::Mtime => {
+                let mut outputs_modified = Vec::new();
+                for path in &self.outputs {
+                    match time_modified(path) {
+                        Some(t) => outputs_modified.push((path, t)),
+                        None => return Some(RebuildReason::OutputMissing(path.clone())),
+                    }
+                }
+                let inputs_modified: Vec<_> =
+                    inputs.iter().filter_map(|p| Some((p, time_modified(p)?))).collect();
+
+                log::trace!("[step {:>4}] outputs {:?}", self.step_id, outputs_modified);
+                log::trace!("[step {:>4}] inputs {:?}", self.step_id, inputs_modified);
+
+                let (output, output_time) = outputs_modified.into_iter().min_by_key(|(_, t)| *t).unwrap();
+                let (input, input_time) =
+                    if let Some(newest) = inputs_modified.into_iter().max_by_key(|(_, t)| *t) {
+                        newest
+                    } else {
+                        return Some(RebuildReason::InputsMissing);
+                    };
+
+                if input_time > output_time {
+                    Some(RebuildReason::InputNewer {
+                        input: input.clone(),
+                        input_time,
+                        output: output.clone(),
+                        output_time,
+                    })
+                } else {
+                    None
+                }
+            },
+            Freshness::Hash => {
+                let previous = if let Some(previous) = previous {
+                    previous
+                } else {
+                    return Some(RebuildReason::NoPreviousRun);
+                };
+                let current = self.current_state(previous.discovered.clone())?;
+                log::trace!("[step {:>4}] previous state {:?}", self.step_id, previous);
+                log::trace!("[step {:>4}] current state {:?}", self.step_id, current);
+                first_difference(&current.inputs, &previous.inputs)
+                    .map(RebuildReason::InputChanged)
+                    .or_else(|| {
+                        first_difference(&current.outputs, &previous.outputs)
+                            .map(RebuildReason::OutputChanged)
+                    })
+            },
+        }
+    }
+
+    /// Command with default settings, running `cmd` in `cwd` on every build
+    pub fn from_args(step_id: StepId, name: &str, cmd: Vec<String>, cwd: PathBuf) -> Self {
+        Self {
+            step_id,
+            name: name.to_owned(),
+            cmd,
+            inputs: None,
+            input_globs: Vec::new(),
+            outputs: Vec::new(),
+            cwd,
+            stdout_pass: false,
+            stderr_pass: false,
+            stream: false,
+            timeout: None,
+            retries: 0,
+            retry_delay: Duration::from_secs(0),
+            stdout_file: None,
+            stderr_file: None,
+            env: HashMap::new(),
+            refresh: false,
+            explain: false,
+            freshness: Freshness::Mtime,
+            depfile: None,
+            previous: None,
+            makeflags: None,
+            cache: None,
+        }
+    }
+
+    /// Only rerun the command when its inputs or outputs change
+    pub fn with_files(mut self, inputs: Option<Vec<PathBuf>>, outputs: Vec<PathBuf>) -> Self {
+        self.inputs = inputs;
+        self.outputs = outputs;
+        self
+    }
+
+    /// Rerun the command up to `retries` times if it fails
+    pub fn with_retries(mut self, retries: u32, retry_delay: Duration) -> Self {
+        self.retries = retries;
+        self.retry_delay = retry_delay;
+        self
+    }
+
+    /// Pass jobserver arguments to the process
+    pub fn with_makeflags(mut self, makeflags: Option<String>) -> Self {
+        self.makeflags = makeflags;
+        self
+    }
+
+    /// Restore outputs from the cache when possible, and store them after running
+    pub fn with_cache(mut self, cache: Option<Cache>) -> Self {
+        self.cache = cache;
+        self
+    }
+
+    pub fn retries(&self) -> u32 {
+        self.retries
+    }
+
+    pub fn retry_delay(&self) -> Duration {
+        self.retry_delay
+    }
+
+    /// Key of this command in the state database
+    fn state_key(&self) -> Option<String> {
+        state_key(&self.outputs)
+    }
+
+    pub fn outputs(&self) -> &[PathBuf] {
+        &self.outputs
+    }
+
+    /// Declared inputs, and the ones discovered from the depfile on the last run
+    pub fn inputs(&self) -> Vec<PathBuf> {
+        let discovered = self.previous.as_ref().map(|p| p.discovered.as_slice()).unwrap_or(&[]);
+        self.all_inputs(discovered).unwrap_or_default()
+    }
+
+    /// Inputs, and the glob patterns among the declared ones, to notice new matching files
+    pub fn watched_inputs(&self) -> Vec<PathBuf> {
+        let mut paths = self.inputs();
+        paths.extend(self.input_globs.iter().cloned());
+        paths
+    }
+
+    /// Key of the outputs in the cache.
+    /// Only commands with declared inputs and outputs are cached.
+    fn cache_key(&self) -> Option<String> {
+        if self.cache.is_none() || self.refresh || self.outputs.is_empty() {
+            return None;
         }
+        let inputs = self.inputs.as_ref()?;
+        let cache = self.cache.as_ref()?;
+        Some(cache.key(&self.cmd, &self.cwd, &self.env, inputs, &self.outputs))
+    }
+
+    fn fingerprint(&self) -> Fingerprint {
+        Fingerprint::new(&self.cmd, &self.cwd, &self.env)
+    }
+
+    /// Declared inputs followed by the ones discovered from the depfile
+    fn all_inputs(&self, discovered: &[PathBuf]) -> Option<Vec<PathBuf>> {
+        match &self.inputs {
+            Some(inputs) => Some(inputs.iter().chain(discovered).cloned().collect()),
+            None if !discovered.is_empty() => Some(discovered.to_vec()),
+            None => None,
+        }
+    }
+
+    /// Inputs listed in the depfile, if any, relative to the working directory
+    fn read_depfile(&self) -> Vec<PathBuf> {
+        let path = if let Some(path) = &self.depfile {
+            path
+        } else {
+            return Vec::new();
+        };
+
+        // Relative paths, both of the depfile and in it, are relative to the working directory
+        match depfile::read(&self.cwd.join(path)) {
+            Ok(paths) => paths.into_iter().map(|p| self.cwd.join(p)).collect(),
+            Err(e) => {
+                log::warn!("[step {:>4}] Unable to read depfile {:?}: {}", self.step_id, path, e);
+                Vec::new()
+            },
+        }
+    }
+
+    /// State of current inputs and outputs, and fingerprint of the command.
+    /// File hashes are only computed in hash freshness mode.
+    fn current_state(&self, discovered: Vec<PathBuf>) -> Option<StepState> {
+        if self.outputs.is_empty() {
+            return None;
+        }
+
+        let mut inputs = BTreeMap::new();
+        let mut outputs = BTreeMap::new();
+        if self.freshness == Freshness::Hash {
+            for path in self.all_inputs(&discovered)? {
+                state::hash_tree(&path, &mut inputs);
+            }
+            for path in &self.outputs {
+                state::hash_tree(path, &mut outputs);
+            }
+        }
+
+        Some(StepState {
+            fingerprint: self.fingerprint(),
+            inputs,
+            outputs,
+            discovered,
+        })
     }
 
-    pub fn new(step_id: StepId, cmd_obj: &PyAny, exec_config: &ExecConfig, env: EnvDict) -> PyResult<Self> {
+    pub fn new(
+        step_id: StepId, name: &str, cmd_obj: &PyAny, exec_config: &ExecConfig, env: EnvDict,
+        state_db: &StateDb,
+    ) -> PyResult<Self>
+    {
         let cmd: Vec<String> = cmd_obj
             .getattr("cmd")?
             .iter()?
@@ -195,30 +762,19 @@ impl Command {
             })
             .collect();
 
-        let py_inputs = cmd_obj.getattr("inputs")?;
-        let inputs: Option<Vec<PathBuf>> = if exec_config.refresh || py_inputs.is_none() {
-            None
-        } else {
-            Some(
-                py_inputs
-                    .iter()?
-                    .flat_map(|c| {
-                        let v = c.ok()?;
-                        if v.is_none() {
-                            return None;
-                        }
-                        Some(Path::new(&v.to_string()).to_owned())
-                    })
-                    .collect(),
-            )
+        let exclude = extract_paths(cmd_obj.getattr("exclude")?)?
+            .unwrap_or_default()
+            .iter()
+            .map(|p| glob_pattern(&p.to_string_lossy()))
+            .collect::<PyResult<Vec<_>>>()?;
+        let declared_inputs = extract_paths(cmd_obj.getattr("inputs")?)?;
+        let input_globs = declared_inputs.iter().flatten().filter(|p| is_glob(p)).cloned().collect();
+        let inputs = match declared_inputs {
+            Some(inputs) => Some(expand_inputs(inputs, &exclude)?),
+            None => None,
         };
 
-        let py_output = cmd_obj.getattr("output")?;
-        let output: Option<PathBuf> = if py_output.is_none() {
-            None
-        } else {
-            Some(Path::new(&py_output.to_string()).to_owned())
-        };
+        let outputs = declared_outputs(cmd_obj)?;
 
         let py_cwd = cmd_obj.getattr("cwd")?;
         let cwd: PathBuf = if py_cwd.is_none() {
@@ -231,7 +787,7 @@ impl Command {
         let cmd_env = if py_env.is_none() {
             EnvDict::new()
         } else {
-            EnvDict::from_pydict(py_env)
+            EnvDict::from_pydict(py_env)?
         };
 
         let py_stdout_file = cmd_obj.getattr("stdout_file")?;
@@ -248,17 +804,143 @@ impl Command {
             Some(Path::new(&py_stderr_file.to_string()).to_owned())
         };
 
+        let py_depfile = cmd_obj.getattr("depfile")?;
+        let depfile: Option<PathBuf> = if py_depfile.is_none() {
+            None
+        } else {
+            Some(Path::new(&py_depfile.to_string()).to_owned())
+        };
+
+        let py_timeout = cmd_obj.getattr("timeout")?;
+        let timeout_secs: Option<f64> = if py_timeout.is_none() {
+            exec_config.timeout
+        } else {
+            Some(py_timeout.extract()?)
+        };
+        let timeout = timeout_secs.map(|secs| duration_from_secs("timeout", secs)).transpose()?;
+
+        let previous = state_key(&outputs).and_then(|key| state_db.get(&key)).cloned();
+
         Ok(Self {
-            step_id,
-            cmd,
-            inputs,
-            output,
-            cwd,
-            stdout_pass: exec_config.transparent,
-            stderr_pass: exec_config.transparent,
+            input_globs,
+            stdout_pass: exec_config.transparent && !exec_config.stream,
+            stderr_pass: exec_config.transparent && !exec_config.stream,
+            stream: exec_config.stream,
+            timeout,
             stdout_file,
             stderr_file,
             env: env.merge(cmd_env).finalize(),
+            refresh: exec_config.refresh,
+            explain: exec_config.explain,
+            freshness: exec_config.freshness(),
+            depfile,
+            previous,
+            ..Self::from_args(step_id, name, cmd, cwd).with_files(inputs, outputs)
         })
     }
 }
+
+/// Capture all lines from a pipe, forwarding them to the console if given
+fn capture_lines<R: Read + Send + 'static>(
+    pipe: R, prefix: String, console: Option<Console>,
+) -> thread::JoinHandle<io::Result<Vec<u8>>> {
+    thread::spawn(move || {
+        let mut reader = BufReader::new(pipe);
+        let mut captured = Vec::new();
+        let mut line = Vec::new();
+        while reader.read_until(b'\n', &mut line)? != 0 {
+            if let Some(console) = &console {
+                let text = String::from_utf8_lossy(&line);
+                console.println(&format!("{} {}", prefix, text.trim_end_matches(&['\r', '\n'][..])));
+            }
+            captured.extend_from_slice(&line);
+            line.clear();
+        }
+        Ok(captured)
+    })
+}
+
+/// Key of a command in the state database, based on its outputs
+pub(crate) fn state_key(outputs: &[PathBuf]) -> Option<String> {
+    if outputs.is_empty() {
+        return None;
+    }
+    let mut paths: Vec<_> = outputs.iter().map(|p| p.to_string_lossy()).collect();
+    paths.sort();
+    Some(paths.join("\n"))
+}
+
+/// Paths from the `outputs` and `output` attributes of a `Cmd` object
+pub(crate) fn declared_outputs(cmd_obj: &PyAny) -> PyResult<Vec<PathBuf>> {
+    let mut outputs = extract_paths(cmd_obj.getattr("outputs")?)?.unwrap_or_default();
+    let py_output = cmd_obj.getattr("output")?;
+    if !py_output.is_none() {
+        outputs.push(Path::new(&py_output.to_string()).to_owned());
+    }
+    Ok(outputs)
+}
+
+/// Convert an optional Python collection of paths, skipping None items
+fn extract_paths(py_paths: &PyAny) -> PyResult<Option<Vec<PathBuf>>> {
+    if py_paths.is_none() {
+        return Ok(None);
+    }
+    Ok(Some(
+        py_paths
+            .iter()?
+            .flat_map(|c| {
+                let v = c.ok()?;
+                if v.is_none() {
+                    return None;
+                }
+                Some(Path::new(&v.to_string()).to_owned())
+            })
+            .collect(),
+    ))
+}
+
+/// Does the path contain glob wildcards
+pub(crate) fn is_glob(path: &Path) -> bool {
+    path.to_string_lossy().contains(&['*', '?', '['][..])
+}
+
+fn glob_pattern(pattern: &str) -> PyResult<glob::Pattern> {
+    glob::Pattern::new(pattern).map_err(|e| pattern_error(pattern, e))
+}
+
+fn pattern_error(pattern: &str, e: glob::PatternError) -> PyErr {
+    PyErr::new::<exceptions::ValueError, _>(format!("Invalid glob pattern {:?}: {}", pattern, e))
+}
+
+/// Expand glob patterns in inputs, and remove paths matching any of the exclude patterns.
+/// When exclude patterns are given, directories are expanded so that the patterns
+/// apply to their contents as well.
+fn expand_inputs(inputs: Vec<PathBuf>, exclude: &[glob::Pattern]) -> PyResult<Vec<PathBuf>> {
+    let mut result = Vec::new();
+    for input in inputs {
+        let s = input.to_string_lossy();
+        if is_glob(&input) {
+            for path in glob::glob(&s).map_err(|e| pattern_error(&s, e))?.flatten() {
+                push_input(path, exclude, &mut result);
+            }
+        } else {
+            push_input(input, exclude, &mut result);
+        }
+    }
+    Ok(result)
+}
+
+fn push_input(path: PathBuf, exclude: &[glob::Pattern], result: &mut Vec<PathBuf>) {
+    if exclude.iter().any(|p| p.matches_path(&path)) {
+        return;
+    }
+    if !exclude.is_empty() && path.is_dir() {
+        if let Ok(entries) = fs::read_dir(&path) {
+            for entry in entries.flatten() {
+                push_input(entry.path(), exclude, result);
+            }
+        }
+    } else {
+        result.push(path);
+    }
+}
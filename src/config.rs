@@ -1,4 +1,4 @@
-use pyo3::{prelude::*, types::*};
+use pyo3::{exceptions, prelude::*, types::*};
 use std::collections::HashSet;
 use std::fs;
 
@@ -6,14 +6,25 @@ use super::depgraph;
 use super::step::{Step, StepId};
 use super::ExecConfig;
 
-/// Read toml and python config files
-pub fn read<'py>(py: Python<'py>, exec_config: &ExecConfig) -> PyResult<(Vec<Step<'py>>, &'py PyDict)> {
+/// Read toml and python config files.
+/// Returns steps, config dictionary and the default target set with `DEFAULT = step_...`, if any.
+pub fn read<'py>(
+    py: Python<'py>, exec_config: &ExecConfig,
+) -> PyResult<(Vec<Step<'py>>, &'py PyDict, Option<String>)> {
     // Import the python configuration
     let py_code_path = exec_config.root_dir().join(&exec_config.python());
     let py_config = if py_code_path.is_dir() {
         let sys = py.import("sys")?;
         let sys_path: &PyList = sys.get("path")?.downcast_ref::<PyList>()?;
         sys_path.insert(0, exec_config.root_dir().to_str().unwrap())?;
+        // Forget earlier imports, so that the configuration can be reloaded
+        let sys_modules: &PyDict = sys.get("modules")?.downcast_ref::<PyDict>()?;
+        for (name, _) in sys_modules.copy()?.iter() {
+            let name = name.to_string();
+            if name == "config" || name.starts_with("config.") {
+                sys_modules.del_item(name)?;
+            }
+        }
         PyModule::import(py, "config")?
     } else {
         let py_code =
@@ -88,8 +99,29 @@ pub fn read<'py>(py: Python<'py>, exec_config: &ExecConfig) -> PyResult<(Vec<Ste
         }
     }
 
+    // Default target
+    let default_target = if let Ok(py_default) = py_config.get("DEFAULT") {
+        let ty = py_default.getattr("__class__")?.getattr("__name__")?.to_string();
+        if ty != "function" {
+            return Err(PyErr::new::<exceptions::ValueError, _>(format!(
+                "DEFAULT must be a step_ function, got {} {}",
+                ty, py_default
+            )));
+        }
+        let fn_name = py_default.getattr("__name__")?.to_string();
+        if !fn_name.starts_with("step_") {
+            return Err(PyErr::new::<exceptions::ValueError, _>(format!(
+                "DEFAULT must be a step_ function, got {}",
+                fn_name
+            )));
+        }
+        Some(fn_name.splitn(2, '_').last().unwrap().to_owned())
+    } else {
+        None
+    };
+
     depgraph::linearize(&mut steps);
-    Ok((steps, &cfg_dict))
+    Ok((steps, &cfg_dict, default_target))
 }
 
 /// `py_step` can be either: FactoryStep, Tuple[FactoryStep], Set[FactoryStep],
@@ -1,8 +1,46 @@
-use serde::Deserialize;
+use serde::{Deserialize, Deserializer};
+use std::collections::HashMap;
 use std::fs;
 use std::path::{Path, PathBuf};
+use std::str::FromStr;
 use structopt::{self, StructOpt};
 
+/// How to decide whether the outputs of a command are fresh
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Freshness {
+    /// Outputs must be newer than all inputs
+    Mtime,
+    /// Content hashes of inputs and outputs, and the command line,
+    /// must match the last successful run
+    Hash,
+}
+impl FromStr for Freshness {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "mtime" => Ok(Self::Mtime),
+            "hash" => Ok(Self::Hash),
+            other => Err(format!("Unknown freshness mode {:?}, expected mtime or hash", other)),
+        }
+    }
+}
+
+/// A single string, as in `target = "name"` of older versions, or a list of strings
+fn one_or_many<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<String>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(String),
+        Many(Vec<String>),
+    }
+    Ok(match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(name) => vec![name],
+        OneOrMany::Many(names) => names,
+    })
+}
+
 #[derive(Debug, Deserialize, StructOpt, Default)]
 #[structopt(rename_all = "kebab-case")]
 #[serde(default, deny_unknown_fields)]
@@ -20,10 +58,31 @@ pub struct ExecConfig {
     #[structopt(short = "-p", long)]
     pub threads: Option<usize>,
 
+    /// Directory for caching command outputs, relative to the root directory
+    #[structopt(long, parse(from_os_str))]
+    pub cache_dir: Option<PathBuf>,
+
+    /// URL of an HTTP server for sharing cached command outputs
+    #[structopt(long)]
+    pub cache_url: Option<String>,
+
     /// Run all commands, even if the output file is fresh
     #[structopt(short, long)]
     pub refresh: bool,
 
+    /// Keep building steps that don't depend on a failed one,
+    /// and report all failures at the end
+    #[structopt(short, long)]
+    pub keep_going: bool,
+
+    /// Default timeout for commands, in seconds
+    #[structopt(long)]
+    pub timeout: Option<f64>,
+
+    /// Freshness check mode: `mtime` (default) or `hash`
+    #[structopt(long)]
+    pub freshness: Option<Freshness>,
+
     /// Disable progress bar and other unnecessary output
     #[structopt(short, long)]
     pub quiet: bool,
@@ -32,6 +91,18 @@ pub struct ExecConfig {
     #[structopt(short, long)]
     pub transparent: bool,
 
+    /// Report why each executed command had to be run
+    #[structopt(long)]
+    pub explain: bool,
+
+    /// Print what would be executed and why, without running any commands
+    #[structopt(short = "-n", long)]
+    pub dry_run: bool,
+
+    /// Display process stdout and stderr live, prefixed with the step name
+    #[structopt(long)]
+    pub stream: bool,
+
     /// Output file for graphviz dot file containing build plan
     #[structopt(short, long, parse(from_os_str))]
     pub plan_dot: Option<PathBuf>,
@@ -40,14 +111,52 @@ pub struct ExecConfig {
     #[structopt(short, long, parse(from_os_str))]
     pub stats_dot: Option<PathBuf>,
 
-    /// Target to execute
-    pub target: Option<String>,
+    /// Output file for a JSON report of all executed steps
+    #[structopt(long, parse(from_os_str))]
+    pub report_json: Option<PathBuf>,
+
+    /// Output file for a Chrome trace of the build, viewable in Perfetto
+    #[structopt(long, parse(from_os_str))]
+    pub trace: Option<PathBuf>,
+
+    /// Output file for a JUnit XML report, with a test suite per target
+    #[structopt(long, parse(from_os_str))]
+    pub junit: Option<PathBuf>,
+
+    /// Print the longest chain of dependent steps after the build
+    #[structopt(long)]
+    pub critical_path: bool,
+
+    /// Share the thread count with child processes using a GNU make jobserver,
+    /// or join the jobserver of a parent make
+    #[structopt(long)]
+    pub jobserver: bool,
+
+    /// After building, rebuild whenever inputs of the targets change
+    #[structopt(short, long)]
+    pub watch: bool,
+
+    /// Targets to execute
+    #[serde(alias = "target", deserialize_with = "one_or_many")]
+    pub targets: Vec<String>,
+
+    /// Target to execute when none are given
+    #[structopt(skip)]
+    pub default_target: Option<String>,
+
+    /// Named resource pools and their capacities, used by `Step.resources`
+    #[structopt(skip)]
+    pub resources: HashMap<String, usize>,
 }
 impl ExecConfig {
     pub fn threads(&self) -> usize {
         self.threads.unwrap_or_else(num_cpus::get)
     }
 
+    pub fn freshness(&self) -> Freshness {
+        self.freshness.unwrap_or(Freshness::Mtime)
+    }
+
     pub fn load_toml(dir_path: &Path) -> Self {
         let contents = fs::read(dir_path.join("Factory.toml")).expect("Factory.toml missing");
         let mut s: Self = toml::from_slice(&contents).expect("Invalid toml");
@@ -68,12 +177,36 @@ impl ExecConfig {
             root_dir: self.root_dir.or(other.root_dir),
             config: self.config.or(other.config),
             threads: self.threads.or(other.threads),
+            cache_dir: self.cache_dir.or(other.cache_dir),
+            cache_url: self.cache_url.or(other.cache_url),
             refresh: self.refresh || other.refresh,
+            keep_going: self.keep_going || other.keep_going,
+            timeout: self.timeout.or(other.timeout),
+            freshness: self.freshness.or(other.freshness),
             quiet: self.quiet || other.quiet,
             transparent: self.transparent || other.transparent,
+            explain: self.explain || other.explain,
+            dry_run: self.dry_run || other.dry_run,
+            stream: self.stream || other.stream,
             plan_dot: self.plan_dot.or(other.plan_dot),
             stats_dot: self.stats_dot.or(other.stats_dot),
-            target: self.target.or(other.target),
+            report_json: self.report_json.or(other.report_json),
+            trace: self.trace.or(other.trace),
+            junit: self.junit.or(other.junit),
+            critical_path: self.critical_path || other.critical_path,
+            jobserver: self.jobserver || other.jobserver,
+            watch: self.watch || other.watch,
+            targets: if self.targets.is_empty() {
+                other.targets
+            } else {
+                self.targets
+            },
+            default_target: self.default_target.or(other.default_target),
+            resources: if self.resources.is_empty() {
+                other.resources
+            } else {
+                self.resources
+            },
         }
     }
 
@@ -85,3 +218,26 @@ impl ExecConfig {
         self.config.clone().unwrap()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn targets_list() {
+        let config: ExecConfig = toml::from_str("targets = [\"a\", \"b\"]").unwrap();
+        assert_eq!(config.targets, vec!["a", "b"]);
+    }
+
+    #[test]
+    fn single_target() {
+        let config: ExecConfig = toml::from_str("target = \"a\"").unwrap();
+        assert_eq!(config.targets, vec!["a"]);
+    }
+
+    #[test]
+    fn no_targets() {
+        let config: ExecConfig = toml::from_str("threads = 2").unwrap();
+        assert!(config.targets.is_empty());
+    }
+}
@@ -0,0 +1,19 @@
+use indicatif::ProgressBar;
+
+/// Line-oriented output that is interleaved with the progress bar
+/// instead of corrupting it. Falls back to plain stderr when the bar is hidden.
+#[derive(Clone)]
+pub struct Console(ProgressBar);
+impl Console {
+    pub fn new(pb: ProgressBar) -> Self {
+        Self(pb)
+    }
+
+    pub fn println(&self, line: &str) {
+        if self.0.is_hidden() {
+            eprintln!("{}", line);
+        } else {
+            self.0.println(line);
+        }
+    }
+}
@@ -0,0 +1,126 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Read prerequisites from a dependency file
+pub fn read(path: &Path) -> io::Result<Vec<PathBuf>> {
+    Ok(parse(&fs::read_to_string(path)?))
+}
+
+/// Parse prerequisites from a Makefile-style dependency file,
+/// e.g. one generated by `gcc -MD`. Targets are ignored.
+pub fn parse(contents: &str) -> Vec<PathBuf> {
+    let mut result: Vec<PathBuf> = Vec::new();
+    let mut in_prerequisites = false;
+    let mut word = String::new();
+
+    let mut end_word = |word: &mut String, in_prerequisites: bool| {
+        if !word.is_empty() {
+            let path = PathBuf::from(word.as_str());
+            if in_prerequisites && !result.contains(&path) {
+                result.push(path);
+            }
+            word.clear();
+        }
+    };
+
+    let mut chars = contents.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.peek() {
+                // Line continuation
+                Some('\n') => {
+                    chars.next();
+                    end_word(&mut word, in_prerequisites);
+                },
+                Some('\r') => {
+                    chars.next();
+                    if chars.peek() == Some(&'\n') {
+                        chars.next();
+                    }
+                    end_word(&mut word, in_prerequisites);
+                },
+                Some(&e @ ' ') | Some(&e @ '#') => {
+                    chars.next();
+                    word.push(e);
+                },
+                _ => word.push(c),
+            },
+            '$' if chars.peek() == Some(&'$') => {
+                chars.next();
+                word.push('$');
+            },
+            ':' if !in_prerequisites && chars.peek().map(|n| n.is_whitespace()).unwrap_or(true) => {
+                end_word(&mut word, in_prerequisites);
+                in_prerequisites = true;
+            },
+            '#' if word.is_empty() => {
+                // Comment until the end of line
+                while let Some(&n) = chars.peek() {
+                    if n == '\n' {
+                        break;
+                    }
+                    chars.next();
+                }
+            },
+            '\n' => {
+                end_word(&mut word, in_prerequisites);
+                in_prerequisites = false;
+            },
+            c if c.is_whitespace() => end_word(&mut word, in_prerequisites),
+            c => word.push(c),
+        }
+    }
+    end_word(&mut word, in_prerequisites);
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn paths(names: &[&str]) -> Vec<PathBuf> {
+        names.iter().map(PathBuf::from).collect()
+    }
+
+    #[test]
+    fn single_rule() {
+        assert_eq!(parse("main.o: main.c main.h\n"), paths(&["main.c", "main.h"]));
+    }
+
+    #[test]
+    fn escaped_spaces() {
+        assert_eq!(parse("out.o: my\\ file.c other.h"), paths(&["my file.c", "other.h"]));
+    }
+
+    #[test]
+    fn line_continuations() {
+        let contents = "main.o: main.c \\\n  a.h \\\r\n  b.h\n";
+        assert_eq!(parse(contents), paths(&["main.c", "a.h", "b.h"]));
+    }
+
+    #[test]
+    fn multiple_targets() {
+        assert_eq!(parse("a.o b.o: shared.h\n"), paths(&["shared.h"]));
+    }
+
+    #[test]
+    fn multiple_rules() {
+        // Phony targets from `-MP` repeat the headers, which are only listed once
+        let contents = "main.o: main.c a.h\na.h:\n";
+        assert_eq!(parse(contents), paths(&["main.c", "a.h"]));
+    }
+
+    #[test]
+    fn comments_and_dollars() {
+        let contents = "# generated\nout: price$$.txt # trailing\n";
+        assert_eq!(parse(contents), paths(&["price$.txt"]));
+    }
+
+    #[test]
+    fn missing_file() {
+        let err = read(Path::new("/nonexistent/factory/test.d")).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+    }
+}
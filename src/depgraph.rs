@@ -1,4 +1,5 @@
 use std::collections::{HashMap, HashSet};
+use std::time::Duration;
 
 use super::{RunStatistics, Step, StepId};
 
@@ -32,10 +33,10 @@ impl IdGraph {
         keys
     }
 
-    /// Return subgraph targeting only the given id
-    pub fn focus(&self, target: StepId) -> Self {
+    /// Return subgraph targeting only the given ids
+    pub fn focus(&self, targets: &[StepId]) -> Self {
         let mut required_steps: HashSet<StepId> = HashSet::new();
-        let mut queue: Vec<StepId> = vec![target];
+        let mut queue: Vec<StepId> = targets.to_vec();
         while let Some(id) = queue.pop() {
             required_steps.insert(id);
             for dep in self.dependencies_of(id).iter() {
@@ -52,27 +53,116 @@ impl IdGraph {
                 .collect(),
         )
     }
+
+    /// Longest chain of dependent steps when weighted by durations, in execution order.
+    /// Steps without a duration are counted as instant.
+    pub fn critical_path(&self, durations: &HashMap<StepId, Duration>) -> Vec<StepId> {
+        let mut memo: HashMap<StepId, (Duration, Option<StepId>)> = HashMap::new();
+        let mut sinks: Vec<StepId> = self.sinks().into_iter().collect();
+        sinks.sort();
+
+        let mut best: Option<(Duration, StepId)> = None;
+        for id in sinks {
+            let length = self.longest_path_to(id, durations, &mut memo);
+            if best.map(|(b, _)| length > b).unwrap_or(true) {
+                best = Some((length, id));
+            }
+        }
+
+        let mut path = Vec::new();
+        let mut current = best.map(|(_, id)| id);
+        while let Some(id) = current {
+            path.push(id);
+            current = memo[&id].1;
+        }
+        path.reverse();
+        path
+    }
+
+    /// Length of the longest path ending at the step, including it.
+    /// Records the length and previous step of the path for each visited step.
+    fn longest_path_to(
+        &self, id: StepId, durations: &HashMap<StepId, Duration>,
+        memo: &mut HashMap<StepId, (Duration, Option<StepId>)>,
+    ) -> Duration
+    {
+        if let Some((length, _)) = memo.get(&id) {
+            return *length;
+        }
+        let mut deps: Vec<StepId> = self.dependencies_of(id).iter().copied().collect();
+        deps.sort();
+        let mut best: Option<(Duration, StepId)> = None;
+        for dep in deps {
+            let length = self.longest_path_to(dep, durations, memo);
+            if best.map(|(b, _)| length > b).unwrap_or(true) {
+                best = Some((length, dep));
+            }
+        }
+        let own = durations.get(&id).copied().unwrap_or_default();
+        let length = best.map(|(b, _)| b).unwrap_or_default() + own;
+        memo.insert(id, (length, best.map(|(_, dep)| dep)));
+        length
+    }
 }
 /// Produce graphviz dot representation of the dependency graph
 pub fn to_dot(steps: &[Step], stats: RunStatistics) -> String {
     let mut dot = String::new();
     dot.push_str("digraph D {\n");
+    let critical: HashSet<StepId> = stats.critical_path.iter().copied().collect();
+    let critical_edges: HashSet<(StepId, StepId)> = stats
+        .critical_path
+        .windows(2)
+        .map(|pair| (pair[0], pair[1]))
+        .collect();
     for s in steps.iter() {
         let stat = stats.commands.get(&s.id);
+        let outputs = stats
+            .outputs
+            .get(&s.id)
+            .filter(|o| !o.is_empty())
+            .map(|o| {
+                let names: Vec<_> = o
+                    .iter()
+                    .map(|p| p.file_name().unwrap_or_else(|| p.as_os_str()).to_string_lossy())
+                    .collect();
+                format!("=> {}\n", names.join(", "))
+            })
+            .unwrap_or_default();
+        let attempts = stat
+            .filter(|st| !st.attempts.is_empty())
+            .map(|st| {
+                let times: Vec<_> = st.attempts.iter().map(|a| format!("{:?}", a.time)).collect();
+                format!("\nretried after {}", times.join(", "))
+            })
+            .unwrap_or_default();
         dot.push_str(&format!(
-            "node{} [shape=box,peripheries={},label=\"{}: {}\n{}{}\"]\n",
+            "node{} [shape=box,peripheries={},color={},penwidth={},label=\"{}: {}\n{}{}{}{}\"]\n",
             s.id,
             if s.target_name.is_some() { 2 } else { 1 },
+            if critical.contains(&s.id) {
+                "red"
+            } else if attempts.is_empty() {
+                "black"
+            } else {
+                "orange"
+            },
+            if critical.contains(&s.id) { 3 } else { 1 },
             s.id,
             s.name,
-            s.note().map(|n| format!("{}\n", n)).unwrap_or_else(String::new),
+            s.note().map(|n| format!("{}\n", n)).unwrap_or_default(),
+            outputs,
             stat.map(|st| format!("{:?} {}", st.time, if st.fresh() { "[fresh]" } else { "" }))
-                .unwrap_or_else(String::new)
+                .unwrap_or_default(),
+            attempts,
         ));
     }
     for s in steps.iter() {
         for r in s.requires.iter() {
-            dot.push_str(&format!("node{} -> node{}\n", r, s.id));
+            if critical_edges.contains(&(*r, s.id)) {
+                dot.push_str(&format!("node{} -> node{} [color=red,penwidth=3]\n", r, s.id));
+            } else {
+                dot.push_str(&format!("node{} -> node{}\n", r, s.id));
+            }
         }
     }
     dot.push_str("}\n");
@@ -143,3 +233,57 @@ fn paths_from(steps: &[Step], cursor: StepId) -> Vec<Vec<StepId>> {
             .collect()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Graph of `count` steps with the given (step, dependency) edges
+    fn graph(count: usize, edges: &[(usize, usize)]) -> (IdGraph, Vec<StepId>) {
+        let mut next = StepId::first();
+        let ids: Vec<StepId> = (0..count).map(|_| next.take()).collect();
+        let mut deps: HashMap<StepId, HashSet<StepId>> = ids.iter().map(|id| (*id, HashSet::new())).collect();
+        for (step, dep) in edges {
+            deps.get_mut(&ids[*step]).unwrap().insert(ids[*dep]);
+        }
+        (IdGraph(deps), ids)
+    }
+
+    fn secs(ids: &[StepId], durations: &[u64]) -> HashMap<StepId, Duration> {
+        ids.iter().zip(durations).map(|(id, s)| (*id, Duration::from_secs(*s))).collect()
+    }
+
+    #[test]
+    fn critical_path_empty() {
+        let (g, _) = graph(0, &[]);
+        assert!(g.critical_path(&HashMap::new()).is_empty());
+    }
+
+    #[test]
+    fn critical_path_chain() {
+        let (g, ids) = graph(3, &[(1, 0), (2, 1)]);
+        assert_eq!(g.critical_path(&secs(&ids, &[1, 1, 1])), ids);
+    }
+
+    #[test]
+    fn critical_path_longest_branch() {
+        // 3 depends on both 1 and 2, which depend on 0
+        let (g, ids) = graph(4, &[(1, 0), (2, 0), (3, 1), (3, 2)]);
+        assert_eq!(g.critical_path(&secs(&ids, &[1, 2, 5, 1])), vec![ids[0], ids[2], ids[3]]);
+        assert_eq!(g.critical_path(&secs(&ids, &[1, 5, 2, 1])), vec![ids[0], ids[1], ids[3]]);
+    }
+
+    #[test]
+    fn critical_path_separate_targets() {
+        let (g, ids) = graph(3, &[(1, 0)]);
+        assert_eq!(g.critical_path(&secs(&ids, &[1, 1, 3])), vec![ids[2]]);
+        assert_eq!(g.critical_path(&secs(&ids, &[2, 2, 3])), vec![ids[0], ids[1]]);
+    }
+
+    #[test]
+    fn critical_path_missing_durations() {
+        let (g, ids) = graph(3, &[(1, 0), (2, 1)]);
+        let durations = secs(&ids[1..], &[4]);
+        assert_eq!(g.critical_path(&durations), ids);
+    }
+}
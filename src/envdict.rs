@@ -1,4 +1,4 @@
-use pyo3::{prelude::*, types::*};
+use pyo3::{exceptions, prelude::*, types::*};
 use std::collections::{HashMap, HashSet};
 
 /// None in value position means that this key must be deleted when merging
@@ -9,10 +9,12 @@ impl EnvDict {
         Self(HashMap::new())
     }
 
-    pub fn from_pydict(py_obj: &PyAny) -> Self {
-        let py_env = py_obj.downcast_ref::<PyDict>().expect("Dictionary expected");
+    pub fn from_pydict(py_obj: &PyAny) -> PyResult<Self> {
+        let py_env = py_obj.downcast_ref::<PyDict>().map_err(|_| {
+            PyErr::new::<exceptions::TypeError, _>(format!("env must be a dict, got {}", py_obj))
+        })?;
 
-        Self(
+        Ok(Self(
             py_env
                 .iter()
                 .map(|(k, v)| {
@@ -22,7 +24,7 @@ impl EnvDict {
                     )
                 })
                 .collect(),
-        )
+        ))
     }
 
     /// Combine self with other, preferring values in self
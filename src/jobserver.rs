@@ -0,0 +1,211 @@
+use crossbeam_channel::{unbounded, Receiver, Sender};
+use std::env;
+use std::fs::OpenOptions;
+use std::io;
+use std::os::unix::io::{IntoRawFd, RawFd};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+/// Environment variables that may contain jobserver file descriptors, in priority order
+const MAKEFLAGS_VARS: &[&str] = &["CARGO_MAKEFLAGS", "MAKEFLAGS", "MFLAGS"];
+
+/// GNU make compatible jobserver, shared with child processes.
+///
+/// Each running job needs a token, except for one implicit token that every
+/// participant owns. Tokens are single bytes read from a pipe, and must be
+/// written back when the job completes.
+pub struct Jobserver {
+    read: RawFd,
+    write: RawFd,
+    /// File descriptors were created by us, instead of inherited
+    owned: bool,
+    /// Value of `MAKEFLAGS` for child processes
+    makeflags: String,
+    /// Acquired token bytes, to be written back on release
+    held: Arc<Mutex<Vec<u8>>>,
+    closed: Arc<AtomicBool>,
+    requests: Sender<()>,
+    acquired: Receiver<()>,
+}
+impl Jobserver {
+    /// Create a new jobserver that allows `jobs` simultaneous jobs
+    pub fn new(jobs: usize) -> io::Result<Self> {
+        let mut fds: [libc::c_int; 2] = [0; 2];
+        if unsafe { libc::pipe(fds.as_mut_ptr()) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let [read, write] = fds;
+        for _ in 1..jobs {
+            write_token(write, b'+')?;
+        }
+        let makeflags = format!(
+            "-j{} --jobserver-fds={},{} --jobserver-auth={},{}",
+            jobs, read, write, read, write
+        );
+        log::debug!("Jobserver created for {} jobs: {}", jobs, makeflags);
+        Ok(Self::with_fds(read, write, true, makeflags))
+    }
+
+    /// Connect to the jobserver of a parent process, if it has passed one
+    pub fn from_env() -> Option<Self> {
+        let makeflags = MAKEFLAGS_VARS.iter().find_map(|var| {
+            env::var(var)
+                .ok()
+                .filter(|v| v.contains("--jobserver-auth=") || v.contains("--jobserver-fds="))
+        })?;
+
+        let auth = makeflags
+            .split_whitespace()
+            .rev()
+            .find_map(|arg| {
+                arg.strip_prefix("--jobserver-auth=")
+                    .or_else(|| arg.strip_prefix("--jobserver-fds="))
+            })?;
+
+        if let Some(path) = auth.strip_prefix("fifo:") {
+            match OpenOptions::new().read(true).write(true).open(path) {
+                Ok(file) => {
+                    let fd = file.into_raw_fd();
+                    log::debug!("Using jobserver fifo {:?}", path);
+                    return Some(Self::with_fds(fd, fd, true, makeflags));
+                },
+                Err(e) => {
+                    log::warn!("Unable to open jobserver fifo {:?}: {}", path, e);
+                    return None;
+                },
+            }
+        }
+
+        let mut parts = auth.splitn(2, ',').map(|p| p.parse::<RawFd>().ok());
+        let (read, write) = match (parts.next().flatten(), parts.next().flatten()) {
+            (Some(read), Some(write)) => (read, write),
+            _ => {
+                log::warn!("Invalid jobserver argument {:?}", auth);
+                return None;
+            },
+        };
+
+        let valid = |fd| unsafe { libc::fcntl(fd, libc::F_GETFD) } != -1;
+        if !valid(read) || !valid(write) {
+            log::warn!("Jobserver file descriptors not available, is the parent rule marked with `+`?");
+            return None;
+        }
+
+        log::debug!("Using inherited jobserver {},{}", read, write);
+        Some(Self::with_fds(read, write, false, makeflags))
+    }
+
+    fn with_fds(read: RawFd, write: RawFd, owned: bool, makeflags: String) -> Self {
+        let held = Arc::new(Mutex::new(Vec::new()));
+        let closed = Arc::new(AtomicBool::new(false));
+        let (requests, request_rx) = unbounded::<()>();
+        let (acquired_tx, acquired) = unbounded::<()>();
+
+        // Reads block, so they are done on a helper thread
+        {
+            let held = held.clone();
+            let closed = closed.clone();
+            thread::spawn(move || {
+                while let Ok(()) = request_rx.recv() {
+                    let token = match read_token(read) {
+                        Ok(token) => token,
+                        Err(e) => {
+                            log::warn!("Jobserver read failed: {}", e);
+                            return;
+                        },
+                    };
+                    if closed.load(Ordering::SeqCst) {
+                        let _ = write_token(write, token);
+                        return;
+                    }
+                    held.lock().unwrap().push(token);
+                    if acquired_tx.send(()).is_err() {
+                        return;
+                    }
+                }
+            });
+        }
+
+        Self {
+            read,
+            write,
+            owned,
+            makeflags,
+            held,
+            closed,
+            requests,
+            acquired,
+        }
+    }
+
+    /// Value for `MAKEFLAGS` and `CARGO_MAKEFLAGS` of child processes
+    pub fn makeflags(&self) -> &str {
+        &self.makeflags
+    }
+
+    /// Start acquiring a token. Signaled on `acquired` when done.
+    pub fn request(&self) {
+        let _ = self.requests.send(());
+    }
+
+    /// Receives a message for every acquired token
+    pub fn acquired(&self) -> &Receiver<()> {
+        &self.acquired
+    }
+
+    /// Return an acquired token to the jobserver
+    pub fn release(&self) -> io::Result<()> {
+        let token = self.held.lock().unwrap().pop();
+        if let Some(token) = token {
+            write_token(self.write, token)?;
+        }
+        Ok(())
+    }
+}
+impl Drop for Jobserver {
+    fn drop(&mut self) {
+        self.closed.store(true, Ordering::SeqCst);
+        for token in self.held.lock().unwrap().drain(..) {
+            let _ = write_token(self.write, token);
+        }
+        if self.owned {
+            unsafe {
+                libc::close(self.read);
+                if self.write != self.read {
+                    libc::close(self.write);
+                }
+            }
+        }
+    }
+}
+
+fn read_token(fd: RawFd) -> io::Result<u8> {
+    let mut buffer = [0u8; 1];
+    loop {
+        let count = unsafe { libc::read(fd, buffer.as_mut_ptr() as *mut libc::c_void, 1) };
+        match count {
+            1 => return Ok(buffer[0]),
+            0 => return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "jobserver closed")),
+            _ => {
+                let err = io::Error::last_os_error();
+                if err.kind() != io::ErrorKind::Interrupted {
+                    return Err(err);
+                }
+            },
+        }
+    }
+}
+
+fn write_token(fd: RawFd, token: u8) -> io::Result<()> {
+    loop {
+        let count = unsafe { libc::write(fd, &token as *const u8 as *const libc::c_void, 1) };
+        if count == 1 {
+            return Ok(());
+        }
+        let err = io::Error::last_os_error();
+        if err.kind() != io::ErrorKind::Interrupted {
+            return Err(err);
+        }
+    }
+}
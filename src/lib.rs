@@ -2,24 +2,39 @@
 #![warn(clippy::all)]
 #![warn(clippy::cargo)]
 
-use crossbeam_channel::{unbounded, Receiver, Sender};
-use pyo3::{prelude::*, types::*};
-use std::collections::HashMap;
+use crossbeam_channel::{select, unbounded, Receiver, Sender};
+use pyo3::{exceptions, prelude::*, types::*};
+use std::collections::{HashMap, HashSet};
 use std::io;
+use std::mem;
+use std::path::PathBuf;
 use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime};
 
 use indicatif::{ProgressBar, ProgressStyle};
 
+pub mod cache;
+pub mod cancel;
 pub mod command;
 pub mod config;
 pub mod config_file;
+pub mod console;
+pub mod depfile;
 pub mod depgraph;
 pub mod envdict;
+pub mod jobserver;
 pub mod parallelize;
+pub mod remote_cache;
+pub mod report;
+pub mod state;
 pub mod step;
+pub mod targets;
+pub mod watch;
 
-use self::command::{Command, CommandResult, CommandResultData};
+use self::command::{duration_from_secs, Command, CommandResult, CommandResultData};
+use self::console::Console;
 use self::envdict::EnvDict;
+use self::state::StateDb;
 use self::step::{Step, StepId};
 
 pub use self::config_file::ExecConfig;
@@ -27,11 +42,44 @@ pub use self::config_file::ExecConfig;
 #[derive(Debug, Default)]
 pub struct RunStatistics {
     pub commands: HashMap<StepId, CommandResult>,
+    /// Declared output files of each executed command
+    pub outputs: HashMap<StepId, Vec<PathBuf>>,
+    /// Input files of each executed command, and glob patterns matching new ones
+    pub inputs: HashMap<StepId, Vec<PathBuf>>,
+    /// Longest chain of dependent steps by actual duration, in execution order
+    pub critical_path: Vec<StepId>,
 }
 impl RunStatistics {
     pub fn new() -> Self {
         Self {
             commands: HashMap::new(),
+            outputs: HashMap::new(),
+            inputs: HashMap::new(),
+            critical_path: Vec::new(),
+        }
+    }
+
+    /// Total time used by each step, including failed attempts
+    pub fn durations(&self) -> HashMap<StepId, Duration> {
+        self.commands
+            .iter()
+            .map(|(id, c)| (*id, c.attempts.iter().map(|a| a.time).sum::<Duration>() + c.time))
+            .collect()
+    }
+
+    /// Print the critical path to stdout
+    pub fn show_critical_path(&self, steps: &[Step]) {
+        let durations = self.durations();
+        let total: Duration = self.critical_path.iter().filter_map(|id| durations.get(id)).sum();
+        println!("Critical path ({:?}):", total);
+        for id in &self.critical_path {
+            let name = steps.iter().find(|s| s.id == *id).map(|s| s.name.as_str()).unwrap_or("");
+            println!(
+                "  [step {:>4}] {} {:?}",
+                id,
+                name,
+                durations.get(id).copied().unwrap_or_default()
+            );
         }
     }
 }
@@ -48,33 +96,136 @@ fn truncate_ellipisis(max_len: usize, s: &str) -> String {
     }
 }
 
-fn find_target_id(steps: &[Step], target_name: &str) -> StepId {
+pub(crate) fn find_target_id(steps: &[Step], target_name: &str) -> Result<StepId, RunError> {
     for step in steps {
         if step.target_name == Some(target_name.to_owned()) {
-            return step.id;
+            return Ok(step.id);
         }
     }
-    panic!("No step named {}", target_name);
+    Err(RunError::UnknownTarget(target_name.to_owned()))
 }
 
 #[derive(Debug)]
 pub enum RunError {
     Python(PyErr),
-    Command(CommandResult),
+    /// Failed commands, more than one only in keep-going mode
+    Command(Vec<CommandResult>),
     Io(io::Error),
+    /// An `Assert` step evaluated to false
+    AssertionFailed {
+        step_id: StepId,
+        step_name: String,
+        message: Option<String>,
+    },
+    /// Step command was not `Cmd`, `Expr` or `Assert`
+    UnknownStepType {
+        step_id: StepId,
+        step_name: String,
+        type_name: String,
+    },
+    /// Step condition didn't evaluate to a boolean
+    InvalidCondition {
+        step_id: StepId,
+        step_name: String,
+        type_name: String,
+    },
+    /// No step with the given target name
+    UnknownTarget(String),
+    /// No targets given and no default configured
+    NoTarget,
+    /// Step uses a resource pool not declared in the configuration
+    UnknownResource {
+        step_id: StepId,
+        step_name: String,
+        resource: String,
+    },
+    /// Build was interrupted by a signal
+    Interrupted,
 }
 impl RunError {
+    /// Process exit code for this kind of error
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Self::Command(_) => 1,
+            Self::Python(_) => 2,
+            Self::Io(_) => 3,
+            Self::AssertionFailed { .. } => 4,
+            Self::UnknownStepType { .. } => 5,
+            Self::InvalidCondition { .. } => 6,
+            Self::UnknownTarget(_) => 7,
+            Self::NoTarget => 8,
+            Self::UnknownResource { .. } => 9,
+            Self::Interrupted => 130,
+        }
+    }
+
     /// Output error state to stderr
     pub fn show(self, py: Python) {
         match self {
             Self::Python(e) => {
                 e.print_and_set_sys_last_vars(py);
             },
-            Self::Command(c) => {
-                c.show();
+            Self::Command(results) => {
+                let count = results.len();
+                for c in results {
+                    c.show();
+                }
+                if count > 1 {
+                    eprintln!("{} steps failed", count);
+                }
+            },
+            Self::Io(e) => {
+                eprintln!("IO error: {}", e);
             },
-            other => {
-                eprintln!("{:?}", other);
+            Self::AssertionFailed {
+                step_id,
+                step_name,
+                message,
+            } => {
+                eprint!("Step {} ({}): Assertion failed", step_id, step_name);
+                if let Some(m) = message {
+                    eprint!(": {}", m);
+                }
+                eprintln!();
+            },
+            Self::UnknownStepType {
+                step_id,
+                step_name,
+                type_name,
+            } => {
+                eprintln!(
+                    "Step {} ({}): Expected Cmd, Expr or Assert, got {}",
+                    step_id, step_name, type_name
+                );
+            },
+            Self::InvalidCondition {
+                step_id,
+                step_name,
+                type_name,
+            } => {
+                eprintln!(
+                    "Step {} ({}): Condition must be a boolean, got {}",
+                    step_id, step_name, type_name
+                );
+            },
+            Self::UnknownTarget(name) => {
+                eprintln!("No target named {:?}", name);
+            },
+            Self::NoTarget => {
+                eprintln!("No target given, and no default target configured");
+            },
+            Self::UnknownResource {
+                step_id,
+                step_name,
+                resource,
+            } => {
+                eprintln!(
+                    "Step {} ({}): Resource {:?} is not declared in Factory.toml",
+                    step_id, step_name, resource
+                );
+            },
+            Self::Interrupted => {
+                eprintln!("Interrupted");
             },
         }
     }
@@ -91,32 +242,116 @@ impl From<PyErr> for RunError {
 }
 
 pub fn run(
-    _py: Python, steps: &[Step], target_name: &str, cfg_dict: &PyDict, exec_config: &ExecConfig,
-    _py_factory: &PyModule,
+    py: Python, steps: &[Step], target_names: &[String], cfg_dict: &PyDict, exec_config: &ExecConfig,
+    py_factory: &PyModule,
 ) -> Result<RunStatistics, RunError>
 {
-    let target = find_target_id(steps, target_name);
+    let mut statistics = RunStatistics::new();
+    run_with(py, steps, target_names, cfg_dict, exec_config, py_factory, &mut statistics)?;
+    Ok(statistics)
+}
+
+/// Like `run`, but collects statistics even if the build fails
+pub fn run_with(
+    py: Python, steps: &[Step], target_names: &[String], cfg_dict: &PyDict, exec_config: &ExecConfig,
+    _py_factory: &PyModule, statistics: &mut RunStatistics,
+) -> Result<(), RunError>
+{
+    let mut failures = Vec::new();
+    let result = run_steps(py, steps, target_names, cfg_dict, exec_config, statistics, &mut failures);
+
+    // Reports are written for failed builds too, as that's when they're needed most
+    let failures = match &result {
+        Err(RunError::Command(results)) => results.as_slice(),
+        _ => failures.as_slice(),
+    };
+    let reports = write_reports(steps, target_names, statistics, failures, exec_config);
+    match (result, reports) {
+        (Err(err), Err(e)) => {
+            log::warn!("Writing reports failed: {}", e);
+            Err(err)
+        },
+        (result, reports) => result.and(reports.map_err(RunError::from)),
+    }
+}
+
+/// Run the steps, collecting failed commands in `failures` until the error is returned
+fn run_steps(
+    _py: Python, steps: &[Step], target_names: &[String], cfg_dict: &PyDict, exec_config: &ExecConfig,
+    statistics: &mut RunStatistics, failures: &mut Vec<CommandResult>,
+) -> Result<(), RunError>
+{
+    let targets = target_names
+        .iter()
+        .map(|name| find_target_id(steps, name))
+        .collect::<Result<Vec<_>, _>>()?;
     let step_by_id: HashMap<StepId, &Step> = steps.iter().map(|s| (s.id, s)).collect();
     let mut dep_graph = depgraph::IdGraph::from_steps(&steps);
-    dep_graph = dep_graph.focus(target);
-    let mut p = parallelize::Parallelizer::from_graph(dep_graph);
+    dep_graph = dep_graph.focus(&targets);
+    let mut state_db = StateDb::load(&exec_config.root_dir());
+    let mut estimates: HashMap<StepId, Duration> = HashMap::new();
+    let mut demands = HashMap::new();
+    for id in dep_graph.nodes() {
+        let step = step_by_id[&id];
+        if let Some(py_obj) = step.py_obj {
+            // Durations are keyed by outputs, which are only known here for plain `Cmd` objects
+            let cmd = py_obj.getattr("cmd")?;
+            if cmd.getattr("__class__")?.getattr("__name__")?.to_string() == "Cmd" {
+                let key = command::state_key(&command::declared_outputs(cmd)?);
+                if let Some(duration) = key.and_then(|key| state_db.duration(&key)) {
+                    estimates.insert(id, duration);
+                }
+            }
+            // Converted by hand, as pyo3 only extracts `HashMap` from 0.8.5 on
+            let py_resources = py_obj.getattr("resources")?.downcast_ref::<PyDict>().map_err(PyErr::from)?;
+            let mut resources: HashMap<String, usize> = HashMap::new();
+            for (name, amount) in py_resources.iter() {
+                resources.insert(name.extract()?, amount.extract()?);
+            }
+            for name in resources.keys() {
+                if !exec_config.resources.contains_key(name) {
+                    return Err(RunError::UnknownResource {
+                        step_id: id,
+                        step_name: step.name.clone(),
+                        resource: name.clone(),
+                    });
+                }
+            }
+            demands.insert(id, parallelize::Demand {
+                weight: py_obj.getattr("weight")?.extract()?,
+                resources,
+            });
+        }
+    }
+    let mut p = parallelize::Parallelizer::from_graph(dep_graph.clone())
+        .with_durations(&estimates)
+        .with_capacity(exec_config.threads(), exec_config.resources.clone(), demands);
+
+    let jobserver = if exec_config.jobserver && !exec_config.dry_run {
+        p = p.with_jobserver();
+        Some(match jobserver::Jobserver::from_env() {
+            Some(js) => js,
+            None => jobserver::Jobserver::new(exec_config.threads())?,
+        })
+    } else {
+        None
+    };
+    let makeflags = jobserver.as_ref().map(|js| js.makeflags().to_owned());
+    let cache = cache::Cache::from_config(exec_config);
+    let tokens = jobserver
+        .as_ref()
+        .map(|js| js.acquired().clone())
+        .unwrap_or_else(crossbeam_channel::never);
+    let mut token_requested = false;
+
+    cancel::install_handler();
 
     let (to_thread, t_recv) = unbounded::<Option<Command>>();
     let (t_send, from_thread) = unbounded::<CommandResult>();
 
     let parallel = exec_config.threads();
 
-    let threads: Vec<JoinHandle<()>> = (0..parallel)
-        .map(|_| {
-            let tx = t_send.clone();
-            let rx = t_recv.clone();
-            thread::spawn(move || runner(rx, tx))
-        })
-        .collect();
-
-    let mut statistics = RunStatistics::new();
-
-    let pb = if exec_config.quiet {
+    let pb = if exec_config.quiet || exec_config.dry_run {
         ProgressBar::hidden()
     } else {
         let p = ProgressBar::new(p.total_count());
@@ -129,6 +364,19 @@ pub fn run(
         p
     };
 
+    let console = Console::new(pb.clone());
+    let threads: Vec<JoinHandle<()>> = (0..parallel)
+        .map(|index| {
+            let tx = t_send.clone();
+            let rx = t_recv.clone();
+            let console = console.clone();
+            thread::spawn(move || runner(index, rx, tx, console))
+        })
+        .collect();
+
+    // Steps that would be executed in a dry run, including through their dependencies
+    let mut would_execute: HashSet<StepId> = HashSet::new();
+
     let size = terminal_size::terminal_size();
     let term_w = if let Some((terminal_size::Width(w), _)) = size {
         w as usize
@@ -154,10 +402,16 @@ pub fn run(
                 ),
             ));
             let step = step_by_id[&step_id];
+            let dependency_executed = exec_config.dry_run
+                && dep_graph.dependencies_of(step_id).iter().any(|dep| would_execute.contains(dep));
+            if dependency_executed {
+                would_execute.insert(step_id);
+            }
             if let Some(py_obj) = step.py_obj {
-                let start = std::time::Instant::now();
+                let started = SystemTime::now();
+                let start = Instant::now();
 
-                let env = EnvDict::from_pydict(py_obj.getattr("env")?);
+                let env = EnvDict::from_pydict(py_obj.getattr("env")?)?;
 
                 let mut cond = py_obj.getattr("condition")?;
                 let mut cond_ty: String = cond.getattr("__class__")?.getattr("__name__")?.to_string();
@@ -165,10 +419,29 @@ pub fn run(
                     cond = cond.call1((cfg_dict,))?;
                     cond_ty = cond.getattr("__class__")?.getattr("__name__")?.to_string();
                 }
-                assert_eq!(cond_ty, "bool", "Condition must be a boolean");
+                if cond_ty.as_str() != "bool" {
+                    pb.abandon_with_message("error");
+                    return Err(RunError::InvalidCondition {
+                        step_id,
+                        step_name: step.name.clone(),
+                        type_name: cond_ty,
+                    });
+                }
                 if !cond.is_true()? {
                     log::info!("[step {:>4}] Skip (condition)", step_id);
+                    if exec_config.dry_run {
+                        println!("[step {:>4}] {}: skip (condition)", step_id, step.name);
+                    }
                     p.mark_complete(step_id);
+                    statistics.commands.insert(step_id, CommandResult {
+                        step_id,
+                        started,
+                        time: start.elapsed(),
+                        data: CommandResultData::ConditionFalse,
+                        state: None,
+                        attempts: Vec::new(),
+                        worker: None,
+                    });
                     continue;
                 }
 
@@ -179,11 +452,30 @@ pub fn run(
                     cmd = cmd.call1((cfg_dict,))?;
                     ty = cmd.getattr("__class__")?.getattr("__name__")?.to_string();
                 }
+                if exec_config.dry_run && ty.as_str() != "Cmd" {
+                    println!("[step {:>4}] {}: evaluate {}", step_id, step.name, ty);
+                }
                 match ty.as_str() {
                     "Cmd" => {
-                        to_thread
-                            .send(Some(Command::new(step_id, cmd, exec_config, env)?))
-                            .unwrap();
+                        let retries: u32 = py_obj.getattr("retries")?.extract()?;
+                        let retry_delay: f64 = py_obj.getattr("retry_delay")?.extract()?;
+                        let command = Command::new(step_id, &step.name, cmd, exec_config, env, &state_db)?
+                            .with_retries(retries, duration_from_secs("retry_delay", retry_delay)?)
+                            .with_makeflags(makeflags.clone())
+                            .with_cache(cache.clone());
+                        statistics.outputs.insert(step_id, command.outputs().to_vec());
+                        statistics.inputs.insert(step_id, command.watched_inputs());
+                        if exec_config.dry_run {
+                            let result = command.dry_run(dependency_executed);
+                            if let CommandResultData::DryRun = result.data {
+                                would_execute.insert(step_id);
+                            }
+                            set_freshvar(step, cfg_dict, result.fresh())?;
+                            p.mark_complete(step_id);
+                            statistics.commands.insert(step_id, result);
+                        } else {
+                            to_thread.send(Some(command)).unwrap();
+                        }
                     },
                     "Expr" => {
                         let expr = cmd.getattr("expr")?;
@@ -193,51 +485,114 @@ pub fn run(
                         p.mark_complete(step_id);
                         statistics.commands.insert(step_id, CommandResult {
                             step_id,
+                            started,
                             time: start.elapsed(),
                             data: CommandResultData::Virtual,
+                            state: None,
+                            attempts: Vec::new(),
+                            worker: None,
                         });
                     },
                     "Assert" => {
                         let expr: bool = cmd.getattr("expr")?.extract()?;
-                        let msg = cmd.getattr("error_msg")?.to_string();
                         if !expr {
-                            // TODO: proper error handling
-                            panic!("STOP {}", msg);
+                            let py_msg = cmd.getattr("error_msg")?;
+                            pb.abandon_with_message("error");
+                            return Err(RunError::AssertionFailed {
+                                step_id,
+                                step_name: step.name.clone(),
+                                message: if py_msg.is_none() { None } else { Some(py_msg.to_string()) },
+                            });
                         }
                         // TODO: Error if freshvar is not None
                         p.mark_complete(step_id);
                         statistics.commands.insert(step_id, CommandResult {
                             step_id,
+                            started,
                             time: start.elapsed(),
                             data: CommandResultData::Virtual,
+                            state: None,
+                            attempts: Vec::new(),
+                            worker: None,
+                        });
+                    },
+                    _ => {
+                        pb.abandon_with_message("error");
+                        return Err(RunError::UnknownStepType {
+                            step_id,
+                            step_name: step.name.clone(),
+                            type_name: ty,
                         });
                     },
-                    _ => unimplemented!("??"),
                 }
             } else {
                 p.mark_complete(step_id);
             }
         }
 
+        if let Some(js) = &jobserver {
+            for _ in 0..p.take_surplus_tokens() {
+                js.release()?;
+            }
+            if !token_requested && p.wants_token() {
+                js.request();
+                token_requested = true;
+            }
+        }
+
         if p.is_done() {
             break;
         } else {
-            let result = from_thread.recv().unwrap();
+            let mut result = select! {
+                recv(from_thread) -> result => result.unwrap(),
+                recv(tokens) -> _ => {
+                    p.add_token();
+                    token_requested = false;
+                    continue;
+                },
+            };
+
+            if cancel::interrupted() {
+                // Wait until all running commands have been killed
+                for _ in 1..p.running_count() {
+                    from_thread.recv().unwrap();
+                }
+                pb.abandon_with_message("interrupted");
+                state_db.save(&exec_config.root_dir())?;
+                return Err(RunError::Interrupted);
+            }
 
             log::trace!("[step {:>4}] Result: {:?}", result.step_id, result);
 
             if !result.success() {
+                if exec_config.keep_going {
+                    let skipped = p.mark_failed(result.step_id);
+                    log::info!(
+                        "[step {:>4}] Failed, skipping dependent steps {:?}",
+                        result.step_id,
+                        skipped
+                    );
+                    failures.push(result);
+                    continue;
+                }
                 pb.abandon_with_message("error");
-                return Err(RunError::Command(result));
+                state_db.save(&exec_config.root_dir())?;
+                return Err(RunError::Command(vec![result]));
             }
 
-            if let Some(py_obj) = step_by_id[&result.step_id].py_obj {
-                let varname = py_obj.getattr("freshvar")?;
-                if !varname.is_none() {
-                    cfg_dict.set_item(varname, result.fresh())?;
+            if let Some((key, state)) = result.state.take() {
+                state_db.insert(key, state);
+            }
+
+            if let CommandResultData::Output(_) = result.data {
+                // Steps without outputs have no stable key, and are not recorded
+                if let Some(key) = command::state_key(&statistics.outputs[&result.step_id]) {
+                    state_db.record_duration(key, result.time);
                 }
             }
 
+            set_freshvar(step_by_id[&result.step_id], cfg_dict, result.fresh())?;
+
             p.mark_complete(result.step_id);
             statistics.commands.insert(result.step_id, result);
         }
@@ -251,14 +606,69 @@ pub fn run(
         t.join().unwrap();
     }
 
+    if !exec_config.dry_run {
+        state_db.save(&exec_config.root_dir())?;
+    }
+
+    if !failures.is_empty() {
+        pb.abandon_with_message("error");
+        return Err(RunError::Command(mem::take(failures)));
+    }
+
     pb.finish_with_message("done");
 
-    Ok(statistics)
+    statistics.critical_path = dep_graph.critical_path(&statistics.durations());
+
+    Ok(())
+}
+
+/// Write the report files requested in the configuration
+fn write_reports(
+    steps: &[Step], target_names: &[String], statistics: &RunStatistics, failures: &[CommandResult],
+    exec_config: &ExecConfig,
+) -> io::Result<()>
+{
+    if let Some(path) = &exec_config.report_json {
+        report::write_json(path, steps, target_names, statistics, failures)?;
+    }
+    if let Some(path) = &exec_config.trace {
+        report::write_trace(path, steps, exec_config.threads(), statistics, failures)?;
+    }
+    if let Some(path) = &exec_config.junit {
+        report::write_junit(path, steps, target_names, statistics, failures)?;
+    }
+    Ok(())
+}
+
+/// Store freshness of a command in the variable named by `Step.freshvar`, if any
+fn set_freshvar(step: &Step, cfg_dict: &PyDict, fresh: bool) -> PyResult<()> {
+    if let Some(py_obj) = step.py_obj {
+        let varname = py_obj.getattr("freshvar")?;
+        if !varname.is_none() {
+            cfg_dict.set_item(varname, fresh)?;
+        }
+    }
+    Ok(())
 }
 
-fn runner(rx: Receiver<Option<Command>>, tx: Sender<CommandResult>) {
+fn runner(index: usize, rx: Receiver<Option<Command>>, tx: Sender<CommandResult>, console: Console) {
     while let Ok(Some(cmd)) = rx.recv() {
-        let result = cmd.run();
+        let mut attempts = Vec::new();
+        let mut result = cmd.run(&console);
+        while !result.success() && attempts.len() < cmd.retries() as usize && !cancel::interrupted() {
+            log::warn!(
+                "[step {:>4}] Failed, retrying ({}/{})",
+                result.step_id,
+                attempts.len() + 1,
+                cmd.retries()
+            );
+            thread::sleep(cmd.retry_delay());
+            result.worker = Some(index);
+            attempts.push(result);
+            result = cmd.retry(&console);
+        }
+        result.attempts = attempts;
+        result.worker = Some(index);
         let send_result = tx.send(result);
         if send_result.is_err() {
             // Send errors only happen if the main thread has crashed,
@@ -273,7 +683,13 @@ fn runner(rx: Receiver<Option<Command>>, tx: Sender<CommandResult>) {
 pub fn check_python(py: Python) -> PyResult<()> {
     let sys = py.import("sys")?;
     let hexversion: u32 = sys.get("hexversion")?.extract()?;
-    assert!(hexversion >= 0x0307_0000); // at least 3.7.0
+    if hexversion < 0x0307_0000 {
+        let version: String = sys.get("version")?.extract()?;
+        return Err(PyErr::new::<exceptions::RuntimeError, _>(format!(
+            "Python 3.7 or newer is required, running {}",
+            version
+        )));
+    }
     Ok(())
 }
 
@@ -3,8 +3,11 @@
 use factory;
 
 use pyo3::prelude::*;
+use std::collections::{HashMap, HashSet};
 use std::env;
 use std::fs;
+use std::io;
+use std::mem;
 use std::path::PathBuf;
 
 use structopt::{self, StructOpt};
@@ -17,6 +20,14 @@ struct Args {
     #[structopt(short, long, parse(from_os_str))]
     directory: Option<PathBuf>,
 
+    /// List available targets instead of running anything
+    #[structopt(long)]
+    list: bool,
+
+    /// Describe a target instead of running anything
+    #[structopt(long)]
+    describe: Option<String>,
+
     #[structopt(flatten)]
     exec: factory::ExecConfig,
 }
@@ -41,24 +52,20 @@ fn inner_main(mut args: Args) -> i32 {
     let gil = Python::acquire_gil();
     let py = gil.python();
 
-    factory::check_python(py)
-        .map_err(|e| {
-            e.print_and_set_sys_last_vars(py);
-        })
-        .unwrap();
+    if let Err(e) = factory::check_python(py) {
+        return show_error(py, e.into());
+    }
 
     // Import class definitions
-    let py_factory = factory::get_py_factory(py)
-        .map_err(|e| {
-            e.print_and_set_sys_last_vars(py);
-        })
-        .unwrap();
+    let py_factory = match factory::get_py_factory(py) {
+        Ok(py_factory) => py_factory,
+        Err(e) => return show_error(py, e.into()),
+    };
 
-    let (steps, cfg_dict) = factory::config::read(py, &args.exec)
-        .map_err(|e| {
-            e.print_and_set_sys_last_vars(py);
-        })
-        .unwrap();
+    let (mut steps, mut cfg_dict, py_default_target) = match factory::config::read(py, &args.exec) {
+        Ok(config) => config,
+        Err(e) => return show_error(py, e.into()),
+    };
 
     if let Some(path) = &args.exec.plan_dot {
         fs::write(
@@ -68,19 +75,149 @@ fn inner_main(mut args: Args) -> i32 {
         .expect("Unable to write `plan_dot` file");
     }
 
-    let target_name = args.exec.target.clone().expect("No target name given");
+    if args.list {
+        for info in factory::targets::list(&steps) {
+            info.show_short();
+        }
+        return 0;
+    }
+
+    if let Some(name) = &args.describe {
+        return match factory::targets::describe(&steps, name) {
+            Ok(info) => {
+                info.show();
+                0
+            },
+            Err(err) => {
+                let code = err.exit_code();
+                err.show(py);
+                code
+            },
+        };
+    }
+
+    let requested = if !args.exec.targets.is_empty() {
+        args.exec.targets.clone()
+    } else if let Some(target) = args.exec.default_target.clone().or(py_default_target) {
+        vec![target]
+    } else {
+        let err = factory::RunError::NoTarget;
+        let code = err.exit_code();
+        err.show(py);
+        return code;
+    };
+
+    let config_path = args.exec.root_dir().join(args.exec.python());
 
-    match factory::run(py, &steps, &target_name, cfg_dict, &args.exec, py_factory) {
-        Ok(stats) => {
-            if let Some(path) = args.exec.stats_dot {
-                fs::write(path, factory::depgraph::to_dot(&steps, stats).as_bytes())
-                    .expect("Unable to write `stats_dot` file");
+    // Created before the first build, so that changes made during a build are noticed
+    let mut watcher = if args.exec.watch {
+        match factory::watch::Watcher::new().and_then(|mut w| w.add(&config_path).map(|()| w)) {
+            Ok(watcher) => Some(watcher),
+            Err(err) => return show_error(py, factory::RunError::Io(err)),
+        }
+    } else {
+        None
+    };
+
+    // Inputs of all steps required by the requested targets, updated for the steps run in each build
+    let mut inputs: HashMap<factory::step::StepId, Vec<PathBuf>> = HashMap::new();
+    let mut targets = requested.clone();
+    loop {
+        let mut stats = factory::RunStatistics::new();
+        let result = factory::run_with(py, &steps, &targets, cfg_dict, &args.exec, py_factory, &mut stats);
+        inputs.extend(mem::take(&mut stats.inputs));
+        let produced = produced_outputs(&stats);
+        let code = match result {
+            Ok(()) => {
+                if args.exec.critical_path {
+                    stats.show_critical_path(&steps);
+                }
+                if let Some(path) = &args.exec.stats_dot {
+                    fs::write(path, factory::depgraph::to_dot(&steps, stats).as_bytes())
+                        .expect("Unable to write `stats_dot` file");
+                }
+                0
+            },
+            Err(err) => show_error(py, err),
+        };
+
+        let watcher = match &mut watcher {
+            Some(watcher) if code != factory::RunError::Interrupted.exit_code() => watcher,
+            _ => return code,
+        };
+
+        // Changes made during the build, except the ones made by the build itself
+        let mut pending = match watcher.take_pending() {
+            Ok(changed) => changed,
+            Err(err) => return show_error(py, factory::RunError::Io(err)),
+        };
+        pending.retain(|path| !produced.iter().any(|output| path.starts_with(output)));
+
+        // Wait until something relevant changes
+        targets = Vec::new();
+        while targets.is_empty() {
+            let changed = if !pending.is_empty() {
+                mem::take(&mut pending)
+            } else {
+                match wait_for_changes(watcher, &inputs) {
+                    Ok(Some(changed)) => changed,
+                    Ok(None) => return factory::RunError::Interrupted.exit_code(),
+                    Err(err) => return show_error(py, factory::RunError::Io(err)),
+                }
+            };
+
+            if changed.iter().any(|path| path.starts_with(&config_path)) {
+                println!("Configuration changed, reloading");
+                match factory::config::read(py, &args.exec) {
+                    Ok((new_steps, new_cfg_dict, _)) => {
+                        steps = new_steps;
+                        cfg_dict = new_cfg_dict;
+                        targets = requested.clone();
+                        // Step ids may have changed, and all targets are rebuilt
+                        inputs.clear();
+                    },
+                    Err(e) => e.print_and_set_sys_last_vars(py),
+                }
+            } else {
+                targets = factory::targets::affected(&steps, &requested, &inputs, &changed);
             }
-            0
-        },
-        Err(err) => {
-            err.show(py);
-            1
-        },
+        }
+        println!("Rebuilding {}", targets.join(", "));
+    }
+}
+
+/// Output the error to stderr, returning the exit code
+fn show_error(py: Python, err: factory::RunError) -> i32 {
+    let code = err.exit_code();
+    err.show(py);
+    code
+}
+
+/// Outputs written by executed or restored steps
+fn produced_outputs(stats: &factory::RunStatistics) -> HashSet<PathBuf> {
+    stats
+        .commands
+        .iter()
+        .filter(|(_, result)| {
+            matches!(
+                result.data,
+                factory::command::CommandResultData::Output(_) | factory::command::CommandResultData::Cached
+            )
+        })
+        .filter_map(|(id, _)| stats.outputs.get(id))
+        .flatten()
+        .cloned()
+        .collect()
+}
+
+/// Watch the inputs, and block until they or the configuration change.
+/// Returns `None` if interrupted.
+fn wait_for_changes(
+    watcher: &mut factory::watch::Watcher, inputs: &HashMap<factory::step::StepId, Vec<PathBuf>>,
+) -> io::Result<Option<HashSet<PathBuf>>> {
+    for path in inputs.values().flatten() {
+        watcher.add(path)?;
     }
+    println!("Watching {} paths for changes", watcher.count());
+    watcher.wait()
 }
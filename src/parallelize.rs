@@ -1,12 +1,43 @@
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
+use std::time::Duration;
 
 use super::depgraph::IdGraph;
 use super::StepId;
 
+/// Capacity used by a step while it's running
+#[derive(Debug, Clone)]
+pub struct Demand {
+    /// Number of worker threads
+    pub weight: usize,
+    /// Amount of each named resource
+    pub resources: HashMap<String, usize>,
+}
+impl Default for Demand {
+    fn default() -> Self {
+        Self {
+            weight: 1,
+            resources: HashMap::new(),
+        }
+    }
+}
+
 pub struct Parallelizer {
     graph: IdGraph,
     pending: HashSet<StepId>,
     running: HashSet<StepId>,
+    /// Estimated duration of the longest path from each step to the end of the build,
+    /// and the number of steps on it
+    priority: HashMap<StepId, (Duration, usize)>,
+    /// Number of worker threads
+    slots: usize,
+    /// Capacities of named resource pools
+    capacity: HashMap<String, usize>,
+    demands: HashMap<StepId, Demand>,
+    /// Total demand of running steps
+    in_use: Demand,
+    /// Jobserver tokens held, in addition to the implicit one.
+    /// `None` if not using a jobserver.
+    tokens: Option<usize>,
 }
 impl Parallelizer {
     pub fn from_graph(graph: IdGraph) -> Self {
@@ -15,9 +46,127 @@ impl Parallelizer {
             graph,
             pending,
             running: HashSet::new(),
+            priority: HashMap::new(),
+            slots: usize::MAX,
+            capacity: HashMap::new(),
+            demands: HashMap::new(),
+            in_use: Demand {
+                weight: 0,
+                resources: HashMap::new(),
+            },
+            tokens: None,
         }
     }
 
+    /// Require a jobserver token for each unit of weight, besides the first one
+    pub fn with_jobserver(mut self) -> Self {
+        self.tokens = Some(0);
+        self
+    }
+
+    /// A jobserver token has been acquired
+    pub fn add_token(&mut self) {
+        if let Some(tokens) = self.tokens.as_mut() {
+            *tokens += 1;
+        }
+    }
+
+    /// Number of held tokens not needed by the running steps.
+    /// These should be released back to the jobserver.
+    pub fn take_surplus_tokens(&mut self) -> usize {
+        let needed = self.in_use.weight.saturating_sub(1);
+        if let Some(tokens) = self.tokens.as_mut() {
+            let surplus = tokens.saturating_sub(needed);
+            *tokens -= surplus;
+            surplus
+        } else {
+            0
+        }
+    }
+
+    /// Is a ready step waiting only for a jobserver token
+    pub fn wants_token(&self) -> bool {
+        self.tokens.is_some()
+            && self
+                .pending
+                .iter()
+                .any(|p| self.is_ready(*p) && !self.fits(*p) && self.fits_without_token(*p))
+    }
+
+    /// Only run steps when there is enough capacity for them.
+    /// Demands exceeding the total capacity are limited to it,
+    /// so that such steps are run alone instead of never.
+    pub fn with_capacity(
+        mut self, slots: usize, capacity: HashMap<String, usize>, demands: HashMap<StepId, Demand>,
+    ) -> Self {
+        self.demands = demands
+            .into_iter()
+            .map(|(id, d)| {
+                let resources = d
+                    .resources
+                    .into_iter()
+                    .map(|(name, amount)| {
+                        let limit = capacity.get(&name).copied().unwrap_or(0);
+                        (name, amount.min(limit))
+                    })
+                    .collect();
+                (id, Demand {
+                    weight: d.weight.min(slots),
+                    resources,
+                })
+            })
+            .collect();
+        self.slots = slots;
+        self.capacity = capacity;
+        self
+    }
+
+    fn demand(&self, step: StepId) -> Demand {
+        self.demands.get(&step).cloned().unwrap_or_default()
+    }
+
+    /// Is there enough free capacity and jobserver tokens to run the step.
+    /// Tokens are not needed if nothing else is running, so that a step
+    /// heavier than the number of available tokens can still run alone.
+    fn fits(&self, step: StepId) -> bool {
+        if let Some(tokens) = self.tokens {
+            if !self.running.is_empty() && self.in_use.weight + self.demand(step).weight > tokens + 1 {
+                return false;
+            }
+        }
+        self.fits_without_token(step)
+    }
+
+    /// Is there enough free capacity to run the step
+    fn fits_without_token(&self, step: StepId) -> bool {
+        let demand = self.demand(step);
+        if self.in_use.weight + demand.weight > self.slots {
+            return false;
+        }
+        demand.resources.iter().all(|(name, amount)| {
+            let used = self.in_use.resources.get(name).copied().unwrap_or(0);
+            let limit = self.capacity.get(name).copied().unwrap_or(0);
+            used + amount <= limit
+        })
+    }
+
+    /// Prioritize steps by the longest remaining path, using estimated durations.
+    /// Steps without an estimate are counted as instant.
+    pub fn with_durations(mut self, durations: &HashMap<StepId, Duration>) -> Self {
+        let mut dependents: HashMap<StepId, Vec<StepId>> = HashMap::new();
+        for id in self.graph.nodes() {
+            for dep in self.graph.dependencies_of(id).iter() {
+                dependents.entry(*dep).or_default().push(id);
+            }
+        }
+        let mut priority = HashMap::new();
+        for id in self.graph.nodes() {
+            remaining_path(id, &dependents, durations, &mut priority);
+        }
+        self.priority = priority;
+        self
+    }
+
     pub fn is_done(&self) -> bool {
         self.pending.is_empty() && self.running.is_empty()
     }
@@ -42,22 +191,146 @@ impl Parallelizer {
         self.running.clone()
     }
 
+    /// All dependencies of the step have been completed
+    fn is_ready(&self, step: StepId) -> bool {
+        self.graph
+            .dependencies_of(step)
+            .iter()
+            .all(|dep| !self.running.contains(dep) && !self.pending.contains(dep))
+    }
+
+    /// Ready step with the longest remaining path, if any
     pub fn get_task(&mut self) -> Option<StepId> {
-        'outer: for p in self.pending.clone().into_iter() {
-            for dep in self.graph.dependencies_of(p).iter() {
-                if self.running.contains(&dep) || self.pending.contains(&dep) {
-                    continue 'outer;
-                }
+        let mut best: Option<((Duration, usize), StepId)> = None;
+        for p in self.pending.iter().copied() {
+            if !self.is_ready(p) || !self.fits(p) {
+                continue;
+            }
+            let priority = self.priority.get(&p).copied().unwrap_or_default();
+            // Prefer lower ids on ties, to keep the order deterministic
+            let better = best
+                .map(|(b, id)| priority > b || (priority == b && p < id))
+                .unwrap_or(true);
+            if better {
+                best = Some((priority, p));
             }
-            self.running.insert(p);
-            self.pending.remove(&p);
-            return Some(p);
         }
-        None
+        let (_, p) = best?;
+        let demand = self.demand(p);
+        self.in_use.weight += demand.weight;
+        for (name, amount) in demand.resources {
+            *self.in_use.resources.entry(name).or_default() += amount;
+        }
+        self.running.insert(p);
+        self.pending.remove(&p);
+        Some(p)
     }
 
     pub fn mark_complete(&mut self, step: StepId) {
         assert!(self.running.contains(&step));
         self.running.remove(&step);
+        let demand = self.demand(step);
+        self.in_use.weight -= demand.weight;
+        for (name, amount) in demand.resources {
+            *self.in_use.resources.get_mut(&name).unwrap() -= amount;
+        }
+    }
+
+    /// Mark step as failed, removing all pending steps that depend on it.
+    /// Returns ids of the removed steps.
+    pub fn mark_failed(&mut self, step: StepId) -> Vec<StepId> {
+        self.mark_complete(step);
+        let mut removed: Vec<StepId> = Vec::new();
+        loop {
+            let blocked: Vec<StepId> = self
+                .pending
+                .iter()
+                .filter(|p| {
+                    self.graph
+                        .dependencies_of(**p)
+                        .iter()
+                        .any(|dep| *dep == step || removed.contains(dep))
+                })
+                .copied()
+                .collect();
+            if blocked.is_empty() {
+                return removed;
+            }
+            for id in blocked {
+                self.pending.remove(&id);
+                removed.push(id);
+            }
+        }
+    }
+}
+
+/// Length of the longest path from the step to the end of the build, including the step itself
+fn remaining_path(
+    id: StepId, dependents: &HashMap<StepId, Vec<StepId>>, durations: &HashMap<StepId, Duration>,
+    memo: &mut HashMap<StepId, (Duration, usize)>,
+) -> (Duration, usize)
+{
+    if let Some(result) = memo.get(&id) {
+        return *result;
+    }
+    let (after, count) = dependents
+        .get(&id)
+        .map(|ds| {
+            ds.iter()
+                .map(|d| remaining_path(*d, dependents, durations, memo))
+                .max()
+                .unwrap_or_default()
+        })
+        .unwrap_or_default();
+    let result = (after + durations.get(&id).copied().unwrap_or_default(), count + 1);
+    memo.insert(id, result);
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ids(count: usize) -> Vec<StepId> {
+        let mut next = StepId::first();
+        (0..count).map(|_| next.take()).collect()
+    }
+
+    #[test]
+    fn remaining_path_single() {
+        let ids = ids(1);
+        let durations = vec![(ids[0], Duration::from_secs(3))].into_iter().collect();
+        let result = remaining_path(ids[0], &HashMap::new(), &durations, &mut HashMap::new());
+        assert_eq!(result, (Duration::from_secs(3), 1));
+    }
+
+    #[test]
+    fn remaining_path_longest_dependent() {
+        // 0 is required by 1 and 2, and 1 is required by 3
+        let ids = ids(4);
+        let dependents = vec![(ids[0], vec![ids[1], ids[2]]), (ids[1], vec![ids[3]])].into_iter().collect();
+        let durations = ids.iter().zip(&[1, 2, 4, 1]).map(|(id, s)| (*id, Duration::from_secs(*s))).collect();
+        let mut memo = HashMap::new();
+        assert_eq!(remaining_path(ids[0], &dependents, &durations, &mut memo), (Duration::from_secs(5), 2));
+        assert_eq!(memo[&ids[1]], (Duration::from_secs(3), 2));
+        assert_eq!(memo[&ids[3]], (Duration::from_secs(1), 1));
+    }
+
+    #[test]
+    fn remaining_path_ties_by_step_count() {
+        // Equally long paths prefer the one with more steps
+        let ids = ids(4);
+        let dependents = vec![(ids[0], vec![ids[1], ids[2]]), (ids[2], vec![ids[3]])].into_iter().collect();
+        let durations = ids.iter().zip(&[1, 2, 1, 1]).map(|(id, s)| (*id, Duration::from_secs(*s))).collect();
+        let result = remaining_path(ids[0], &dependents, &durations, &mut HashMap::new());
+        assert_eq!(result, (Duration::from_secs(3), 3));
+    }
+
+    #[test]
+    fn remaining_path_missing_duration() {
+        let ids = ids(2);
+        let dependents = vec![(ids[0], vec![ids[1]])].into_iter().collect();
+        let result = remaining_path(ids[0], &dependents, &HashMap::new(), &mut HashMap::new());
+        assert_eq!(result, (Duration::from_secs(0), 2));
     }
 }
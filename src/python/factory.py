@@ -10,12 +10,16 @@ class Cmd:
     """A command to be executed."""
 
     cmd: List[Union[Path, str, None]]  # TODO: lists, flattening?
-    inputs: Optional[Set[Path]] = None  # TODO: lists, flattening?
+    inputs: Optional[Set[Union[Path, str]]] = None  # TODO: lists, flattening?
+    exclude: Optional[Set[Union[Path, str]]] = None
     output: Optional[Path] = None
+    outputs: Union[None, Set[Path], List[Path]] = None
     cwd: Optional[Path] = None
     env: EnvDict = field(default_factory=dict)
     stdout_file: Optional[Path] = None
     stderr_file: Optional[Path] = None
+    depfile: Optional[Path] = None
+    timeout: Optional[float] = None  # Seconds
 
     def __hash__(self):
         return hash(repr(self))
@@ -56,6 +60,14 @@ class Step:
     freshvar: Union[None, str, Callable[[EnvDict], str]] = None
     condition: Union[bool, Callable[[EnvDict], bool]] = True
     note: Union[None, str] = None
+    retries: int = 0
+    retry_delay: float = 0.0  # Seconds
+    weight: int = 1  # Worker threads reserved while running
+    resources: Dict[str, int] = field(default_factory=dict)  # Pools declared in Factory.toml
+
+    def __post_init__(self):
+        if self.weight < 1:
+            raise ValueError("Step weight must be at least 1, got {}".format(self.weight))
 
     def __hash__(self):
         return hash(repr(self))
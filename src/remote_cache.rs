@@ -0,0 +1,374 @@
+use std::io::{self, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+use crate::cache::CacheBackend;
+
+/// Timeout for connecting and for each read and write
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Blobs stored on an HTTP server, with `GET` and `PUT` requests to `<url>/<key>`.
+/// Only plain `http://` is supported.
+///
+/// After the first connection failure the backend is disabled for the rest of the
+/// run, so that an unreachable server doesn't slow down every step.
+#[derive(Debug)]
+pub struct HttpBackend {
+    /// Host and port, e.g. `localhost:8080`
+    host: String,
+    /// Path prefix without a trailing slash, e.g. `/cache`
+    prefix: String,
+    disabled: AtomicBool,
+}
+impl HttpBackend {
+    pub fn new(url: &str) -> io::Result<Self> {
+        let rest = url.strip_prefix("http://").ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Unsupported cache URL {:?}, only http:// is supported", url),
+            )
+        })?;
+        let (host, prefix) = match rest.find('/') {
+            Some(i) => (&rest[..i], rest[i..].trim_end_matches('/')),
+            None => (rest, ""),
+        };
+        if host.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Missing host in cache URL {:?}", url),
+            ));
+        }
+        let host = if host.contains(':') {
+            host.to_owned()
+        } else {
+            format!("{}:80", host)
+        };
+        Ok(Self {
+            host,
+            prefix: prefix.to_owned(),
+            disabled: AtomicBool::new(false),
+        })
+    }
+
+    /// Send a request, returning status code and body
+    fn request(&self, method: &str, key: &str, body: &[u8]) -> io::Result<(u16, Vec<u8>)> {
+        if self.disabled.load(Ordering::SeqCst) {
+            return Err(io::Error::new(io::ErrorKind::NotConnected, "disabled after an earlier error"));
+        }
+        self.send(method, key, body).map_err(|e| {
+            if !self.disabled.swap(true, Ordering::SeqCst) {
+                log::warn!("Remote cache {} disabled: {}", self.describe(), e);
+            }
+            e
+        })
+    }
+
+    fn send(&self, method: &str, key: &str, body: &[u8]) -> io::Result<(u16, Vec<u8>)> {
+        let addr = self
+            .host
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "host not found"))?;
+        let mut stream = TcpStream::connect_timeout(&addr, TIMEOUT)?;
+        stream.set_read_timeout(Some(TIMEOUT))?;
+        stream.set_write_timeout(Some(TIMEOUT))?;
+
+        let header = format!(
+            "{} {}/{} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\nContent-Length: {}\r\n\r\n",
+            method,
+            self.prefix,
+            key,
+            self.host,
+            body.len()
+        );
+        stream.write_all(header.as_bytes())?;
+        stream.write_all(body)?;
+        stream.flush()?;
+
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response)?;
+        parse_response(&response, method != "HEAD")
+    }
+}
+impl CacheBackend for HttpBackend {
+    fn describe(&self) -> String {
+        format!("http://{}{}", self.host, self.prefix)
+    }
+
+    fn get(&self, key: &str) -> io::Result<Option<Vec<u8>>> {
+        match self.request("GET", key, &[])? {
+            (200, body) => Ok(Some(body)),
+            (404, _) => Ok(None),
+            (status, _) => Err(status_error("GET", status)),
+        }
+    }
+
+    fn put(&self, key: &str, data: &[u8]) -> io::Result<()> {
+        match self.request("PUT", key, data)? {
+            (status, _) if (200..300).contains(&status) => Ok(()),
+            (status, _) => Err(status_error("PUT", status)),
+        }
+    }
+
+    fn contains(&self, key: &str) -> io::Result<bool> {
+        match self.request("HEAD", key, &[])? {
+            (200, _) => Ok(true),
+            (404, _) => Ok(false),
+            (status, _) => Err(status_error("HEAD", status)),
+        }
+    }
+}
+
+fn status_error(method: &str, status: u16) -> io::Error {
+    io::Error::other(format!("{} failed with status {}", method, status))
+}
+
+fn invalid_response(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("Invalid HTTP response: {}", message))
+}
+
+/// Parse a complete HTTP/1.x response, returning status code and body.
+/// Responses to `HEAD` requests have no body, even if they have a `Content-Length`.
+fn parse_response(response: &[u8], has_body: bool) -> io::Result<(u16, Vec<u8>)> {
+    let header_end = response
+        .windows(4)
+        .position(|w| w == b"\r\n\r\n")
+        .ok_or_else(|| invalid_response("incomplete header"))?;
+    let header = String::from_utf8_lossy(&response[..header_end]);
+    let body = &response[header_end + 4..];
+
+    let mut lines = header.split("\r\n");
+    let status: u16 = lines
+        .next()
+        .and_then(|line| line.split_whitespace().nth(1))
+        .and_then(|code| code.parse().ok())
+        .ok_or_else(|| invalid_response("missing status"))?;
+
+    let mut content_length = None;
+    let mut chunked = false;
+    for line in lines {
+        let mut parts = line.splitn(2, ':');
+        let name = parts.next().unwrap_or("").trim().to_ascii_lowercase();
+        let value = parts.next().unwrap_or("").trim();
+        match name.as_str() {
+            "content-length" => content_length = value.parse::<usize>().ok(),
+            "transfer-encoding" => chunked = value.eq_ignore_ascii_case("chunked"),
+            _ => {},
+        }
+    }
+
+    let body = if !has_body {
+        Vec::new()
+    } else if chunked {
+        decode_chunked(body)?
+    } else if let Some(length) = content_length {
+        if body.len() < length {
+            return Err(invalid_response("truncated body"));
+        }
+        body[..length].to_vec()
+    } else {
+        body.to_vec()
+    };
+    Ok((status, body))
+}
+
+/// Decode a body using chunked transfer encoding
+fn decode_chunked(mut data: &[u8]) -> io::Result<Vec<u8>> {
+    let mut result = Vec::new();
+    loop {
+        let line_end = data
+            .windows(2)
+            .position(|w| w == b"\r\n")
+            .ok_or_else(|| invalid_response("incomplete chunk"))?;
+        let size_line = String::from_utf8_lossy(&data[..line_end]);
+        // Chunk extensions after `;` are ignored
+        let size_hex = size_line.split(';').next().unwrap_or("").trim();
+        let size =
+            usize::from_str_radix(size_hex, 16).map_err(|_| invalid_response("invalid chunk size"))?;
+        data = &data[line_end + 2..];
+        if size == 0 {
+            return Ok(result);
+        }
+        if data.len() < size + 2 {
+            return Err(invalid_response("truncated chunk"));
+        }
+        result.extend_from_slice(&data[..size]);
+        data = &data[size + 2..];
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::fs;
+    use std::net::TcpListener;
+    use std::path::PathBuf;
+    use std::process;
+    use std::sync::Arc;
+    use std::thread;
+
+    use crate::cache::Cache;
+    use crate::command::{Command, CommandResultData};
+    use crate::console::Console;
+    use crate::step::StepId;
+
+    /// Serve blobs from memory on a free local port, returning the cache URL.
+    /// With `chunked`, `GET` responses use chunked transfer encoding.
+    fn serve(chunked: bool) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/cache", listener.local_addr().unwrap());
+        thread::spawn(move || {
+            let mut blobs = HashMap::new();
+            for stream in listener.incoming() {
+                handle(stream.unwrap(), &mut blobs, chunked);
+            }
+        });
+        url
+    }
+
+    fn handle(mut stream: TcpStream, blobs: &mut HashMap<String, Vec<u8>>, chunked: bool) {
+        let mut header = Vec::new();
+        let mut byte = [0u8; 1];
+        while !header.ends_with(b"\r\n\r\n") {
+            stream.read_exact(&mut byte).unwrap();
+            header.push(byte[0]);
+        }
+        let header = String::from_utf8(header).unwrap();
+        let mut words = header.split_whitespace();
+        let method = words.next().unwrap().to_owned();
+        let path = words.next().unwrap().to_owned();
+        let length = header
+            .lines()
+            .find_map(|line| line.strip_prefix("Content-Length: "))
+            .map(|value| value.parse().unwrap())
+            .unwrap_or(0);
+        let mut body = vec![0u8; length];
+        stream.read_exact(&mut body).unwrap();
+
+        let response = match (method.as_str(), blobs.get(&path)) {
+            ("PUT", _) => {
+                blobs.insert(path, body);
+                b"HTTP/1.1 201 Created\r\nContent-Length: 0\r\n\r\n".to_vec()
+            },
+            ("HEAD", Some(data)) => {
+                format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n", data.len()).into_bytes()
+            },
+            ("GET", Some(data)) if chunked => {
+                let mut response = b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n".to_vec();
+                for chunk in data.chunks(4) {
+                    response.extend(format!("{:x}\r\n", chunk.len()).bytes());
+                    response.extend(chunk);
+                    response.extend(b"\r\n");
+                }
+                response.extend(b"0\r\n\r\n");
+                response
+            },
+            ("GET", Some(data)) => {
+                let mut response =
+                    format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n", data.len()).into_bytes();
+                response.extend(data);
+                response
+            },
+            _ => b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\r\n".to_vec(),
+        };
+        stream.write_all(&response).unwrap();
+    }
+
+    /// Empty directory for a test
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("factory-{}-{}", name, process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn parse_content_length() {
+        let response = b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nhello, extra";
+        assert_eq!(parse_response(response, true).unwrap(), (200, b"hello".to_vec()));
+        let response = b"HTTP/1.1 200 OK\r\nContent-Length: 10\r\n\r\nhello";
+        assert!(parse_response(response, true).is_err());
+    }
+
+    #[test]
+    fn parse_without_body() {
+        // Responses to `HEAD` have a `Content-Length` but no body
+        let response = b"HTTP/1.1 200 OK\r\nContent-Length: 10\r\n\r\n";
+        assert_eq!(parse_response(response, false).unwrap(), (200, Vec::new()));
+        let response = b"HTTP/1.0 404 Not Found\r\nConnection: close\r\n\r\nnot found";
+        assert_eq!(parse_response(response, true).unwrap(), (404, b"not found".to_vec()));
+    }
+
+    #[test]
+    fn parse_invalid_header() {
+        assert!(parse_response(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n", true).is_err());
+        assert!(parse_response(b"\r\n\r\n", true).is_err());
+        assert!(parse_response(b"HTTP/1.1 OK\r\n\r\n", true).is_err());
+    }
+
+    #[test]
+    fn parse_chunked() {
+        let response = b"HTTP/1.1 200 OK\r\ntransfer-encoding: Chunked\r\n\r\n5\r\nhello\r\n0\r\n\r\n";
+        assert_eq!(parse_response(response, true).unwrap(), (200, b"hello".to_vec()));
+    }
+
+    #[test]
+    fn chunk_extensions() {
+        let body = b"4;name=value\r\nsome\r\nA ; ext\r\n more data\r\n0\r\n\r\n";
+        assert_eq!(decode_chunked(body).unwrap(), b"some more data".to_vec());
+    }
+
+    #[test]
+    fn invalid_chunks() {
+        assert!(decode_chunked(b"a\r\nshort\r\n0\r\n\r\n").is_err());
+        assert!(decode_chunked(b"5\r\nhello\r\n").is_err());
+        assert!(decode_chunked(b"zz\r\nhello\r\n0\r\n\r\n").is_err());
+    }
+
+    #[test]
+    fn put_then_get() {
+        let backend = HttpBackend::new(&serve(false)).unwrap();
+        backend.put("objects/ab/abcd", b"contents").unwrap();
+        assert!(backend.contains("objects/ab/abcd").unwrap());
+        assert_eq!(backend.get("objects/ab/abcd").unwrap(), Some(b"contents".to_vec()));
+    }
+
+    #[test]
+    fn missing_blob() {
+        let backend = HttpBackend::new(&serve(false)).unwrap();
+        assert!(!backend.contains("entries/missing.json").unwrap());
+        assert_eq!(backend.get("entries/missing.json").unwrap(), None);
+    }
+
+    #[test]
+    fn chunked_response() {
+        let backend = HttpBackend::new(&serve(true)).unwrap();
+        let data = b"split into several chunks".to_vec();
+        backend.put("objects/cd/cdef", &data).unwrap();
+        assert_eq!(backend.get("objects/cd/cdef").unwrap(), Some(data));
+    }
+
+    #[test]
+    fn unreachable_server_runs_command() {
+        // Nothing listens on the port after the listener is dropped
+        let addr = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let backend = Arc::new(HttpBackend::new(&format!("http://{}", addr)).unwrap());
+        let dir = test_dir("unreachable");
+        fs::write(dir.join("input"), "contents").unwrap();
+
+        let cache = Cache::new(vec![backend.clone()], dir.clone());
+        let cmd = vec!["sh".to_owned(), "-c".to_owned(), "cp input output".to_owned()];
+        let command = Command::from_args(StepId::first(), "copy", cmd, dir.clone())
+            .with_files(Some(vec![dir.join("input")]), vec![dir.join("output")])
+            .with_cache(Some(cache));
+        let result = command.run(&Console::new(indicatif::ProgressBar::hidden()));
+
+        assert!(result.success());
+        assert!(matches!(result.data, CommandResultData::Output(_)));
+        assert_eq!(fs::read_to_string(dir.join("output")).unwrap(), "contents");
+        assert!(backend.disabled.load(Ordering::SeqCst));
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
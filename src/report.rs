@@ -0,0 +1,322 @@
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::command::{CommandResult, CommandResultData};
+use super::depgraph::IdGraph;
+use super::{find_target_id, RunStatistics, Step, StepId};
+
+/// Version of the JSON report format, increased on incompatible changes
+const REPORT_VERSION: u32 = 1;
+
+#[derive(Debug, Serialize)]
+struct Report<'a> {
+    version: u32,
+    targets: &'a [String],
+    success: bool,
+    steps: Vec<StepReport>,
+}
+
+#[derive(Debug, Serialize)]
+struct StepReport {
+    id: StepId,
+    name: String,
+    /// Target name, if the step can be built as a target
+    target: Option<String>,
+    /// `Fresh`, `Cached`, `Output`, `Virtual`, `ConditionFalse`, `DryRun` or `Failed`
+    status: &'static str,
+    /// Process exit code, if the command was executed and exited normally
+    exit_code: Option<i32>,
+    /// Seconds since the Unix epoch
+    start: f64,
+    /// Seconds since the Unix epoch
+    end: f64,
+    /// Seconds
+    duration: f64,
+    /// Index of the worker thread, if the step was run on one
+    worker: Option<usize>,
+    /// Number of times the command was run
+    attempts: usize,
+}
+impl StepReport {
+    fn new(step: &Step, result: &CommandResult) -> Self {
+        let start = timestamp(result.started);
+        let duration = result.time.as_secs_f64();
+        Self {
+            id: step.id,
+            name: step.name.clone(),
+            target: step.target_name.clone(),
+            status: status(result),
+            exit_code: match &result.data {
+                CommandResultData::Output(out) | CommandResultData::TimedOut(out) => out.status.code(),
+                _ => None,
+            },
+            start,
+            end: start + duration,
+            duration,
+            worker: result.worker,
+            attempts: result.attempts.len() + 1,
+        }
+    }
+}
+
+/// Event in the Chrome Trace Event Format
+#[derive(Debug, Serialize)]
+struct TraceEvent {
+    name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cat: Option<&'static str>,
+    ph: &'static str,
+    /// Microseconds since the start of the build
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ts: Option<f64>,
+    /// Microseconds
+    #[serde(skip_serializing_if = "Option::is_none")]
+    dur: Option<f64>,
+    pid: u32,
+    tid: usize,
+    args: BTreeMap<&'static str, serde_json::Value>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Trace {
+    trace_events: Vec<TraceEvent>,
+    display_time_unit: &'static str,
+}
+
+/// Trace thread id: zero for the main thread, workers are numbered from one
+fn trace_tid(result: &CommandResult) -> usize {
+    result.worker.map(|w| w + 1).unwrap_or(0)
+}
+
+/// Write a Chrome Trace Event Format file with a track per worker thread,
+/// viewable e.g. in Perfetto or `chrome://tracing`
+pub fn write_trace(
+    path: &Path, steps: &[Step], threads: usize, stats: &RunStatistics, failures: &[CommandResult],
+) -> io::Result<()> {
+    let results = results(steps, stats, failures);
+
+    let origin = results
+        .iter()
+        .flat_map(|(_, r)| r.attempts.iter().chain(Some(*r)))
+        .map(|r| timestamp(r.started))
+        .fold(f64::INFINITY, f64::min);
+
+    let mut events = Vec::new();
+    for tid in 0..=threads {
+        let mut args = BTreeMap::new();
+        let name = if tid == 0 {
+            "main".to_owned()
+        } else {
+            format!("worker {}", tid - 1)
+        };
+        args.insert("name", name.into());
+        events.push(TraceEvent {
+            name: "thread_name".to_owned(),
+            cat: None,
+            ph: "M",
+            ts: None,
+            dur: None,
+            pid: 1,
+            tid,
+            args,
+        });
+    }
+
+    for (step, result) in results {
+        for (attempt, r) in result.attempts.iter().chain(Some(result)).enumerate() {
+            let mut args = BTreeMap::new();
+            args.insert("id", step.id.to_string().into());
+            args.insert("status", status(r).into());
+            args.insert("attempt", (attempt + 1).into());
+            if let Some(target) = &step.target_name {
+                args.insert("target", target.clone().into());
+            }
+            events.push(TraceEvent {
+                name: step.name.clone(),
+                cat: Some(status(r)),
+                ph: "X",
+                ts: Some((timestamp(r.started) - origin) * 1e6),
+                dur: Some(r.time.as_secs_f64() * 1e6),
+                pid: 1,
+                tid: trace_tid(r),
+                args,
+            });
+        }
+    }
+
+    let trace = Trace {
+        trace_events: events,
+        display_time_unit: "ms",
+    };
+    fs::write(path, serde_json::to_vec(&trace)?)
+}
+
+/// Write a JUnit XML file with a test suite per target,
+/// and a test case for each executed step required by it
+pub fn write_junit(
+    path: &Path, steps: &[Step], targets: &[String], stats: &RunStatistics, failures: &[CommandResult],
+) -> io::Result<()> {
+    let graph = IdGraph::from_steps(steps);
+    let results = results(steps, stats, failures);
+
+    let mut xml = String::new();
+    xml.push_str("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    xml.push_str("<testsuites>\n");
+    for target in targets {
+        let required = match find_target_id(steps, target) {
+            Ok(id) => graph.focus(&[id]).nodes(),
+            Err(_) => continue,
+        };
+        let cases: Vec<_> = results.iter().filter(|(s, _)| required.contains(&s.id)).collect();
+        let failed = cases.iter().filter(|(_, r)| !r.success()).count();
+        let skipped = cases.iter().filter(|(_, r)| status(r) == "ConditionFalse").count();
+        let time: f64 = cases.iter().map(|(_, r)| r.time.as_secs_f64()).sum();
+
+        xml.push_str(&format!(
+            "  <testsuite name=\"{}\" tests=\"{}\" failures=\"{}\" skipped=\"{}\" time=\"{:.3}\">\n",
+            xml_escape(target),
+            cases.len(),
+            failed,
+            skipped,
+            time
+        ));
+        for (step, result) in cases {
+            xml.push_str(&format!(
+                "    <testcase classname=\"{}\" name=\"step {}\" time=\"{:.3}\"",
+                xml_escape(&step.name),
+                step.id,
+                result.time.as_secs_f64()
+            ));
+            let stderr = match &result.data {
+                CommandResultData::Output(out) | CommandResultData::TimedOut(out) => {
+                    String::from_utf8_lossy(&out.stderr).into_owned()
+                },
+                _ => String::new(),
+            };
+            if !result.success() {
+                let message = match &result.data {
+                    CommandResultData::Output(out) => match out.status.code() {
+                        Some(code) => format!("exit code {}", code),
+                        None => "killed by a signal".to_owned(),
+                    },
+                    CommandResultData::TimedOut(_) => "timed out".to_owned(),
+                    CommandResultData::Cancelled => "cancelled".to_owned(),
+                    other => format!("{:?}", other),
+                };
+                xml.push_str(&format!(
+                    ">\n      <failure message=\"{}\">{}</failure>\n    </testcase>\n",
+                    xml_escape(&message),
+                    xml_escape(&stderr)
+                ));
+            } else if let CommandResultData::ConditionFalse = result.data {
+                xml.push_str(">\n      <skipped message=\"condition is false\"/>\n    </testcase>\n");
+            } else if !stderr.is_empty() {
+                xml.push_str(&format!(
+                    ">\n      <system-err>{}</system-err>\n    </testcase>\n",
+                    xml_escape(&stderr)
+                ));
+            } else {
+                xml.push_str("/>\n");
+            }
+        }
+        xml.push_str("  </testsuite>\n");
+    }
+    xml.push_str("</testsuites>\n");
+    fs::write(path, xml)
+}
+
+/// Escape text for XML attributes and character data
+fn xml_escape(s: &str) -> String {
+    let mut result = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => result.push_str("&amp;"),
+            '<' => result.push_str("&lt;"),
+            '>' => result.push_str("&gt;"),
+            '"' => result.push_str("&quot;"),
+            '\'' => result.push_str("&apos;"),
+            // Control characters are not allowed in XML 1.0
+            c if c.is_control() && c != '\n' && c != '\t' && c != '\r' => {},
+            c => result.push(c),
+        }
+    }
+    result
+}
+
+/// Short name of the result kind
+pub fn status(result: &CommandResult) -> &'static str {
+    if !result.success() {
+        return "Failed";
+    }
+    match result.data {
+        CommandResultData::Fresh => "Fresh",
+        CommandResultData::Cached => "Cached",
+        CommandResultData::Output(_) => "Output",
+        CommandResultData::DryRun => "DryRun",
+        CommandResultData::Virtual => "Virtual",
+        CommandResultData::ConditionFalse => "ConditionFalse",
+        CommandResultData::TimedOut(_) | CommandResultData::Cancelled => "Failed",
+    }
+}
+
+/// Seconds since the Unix epoch
+pub fn timestamp(t: SystemTime) -> f64 {
+    t.duration_since(UNIX_EPOCH).map(|d| d.as_secs_f64()).unwrap_or(0.0)
+}
+
+/// Results of all executed steps, including failed ones, ordered by step id
+pub fn results<'a>(
+    steps: &'a [Step], stats: &'a RunStatistics, failures: &'a [CommandResult],
+) -> Vec<(&'a Step<'a>, &'a CommandResult)> {
+    let failed: HashMap<StepId, &CommandResult> = failures.iter().map(|r| (r.step_id, r)).collect();
+    let mut result: Vec<_> = steps
+        .iter()
+        .filter_map(|s| Some((s, stats.commands.get(&s.id).or_else(|| failed.get(&s.id).cloned())?)))
+        .collect();
+    result.sort_by_key(|(s, _)| s.id);
+    result
+}
+
+/// Write a JSON report of the build
+pub fn write_json(
+    path: &Path, steps: &[Step], targets: &[String], stats: &RunStatistics, failures: &[CommandResult],
+) -> io::Result<()> {
+    let report = Report {
+        version: REPORT_VERSION,
+        targets,
+        success: failures.is_empty(),
+        steps: results(steps, stats, failures)
+            .into_iter()
+            .map(|(step, result)| StepReport::new(step, result))
+            .collect(),
+    };
+    fs::write(path, serde_json::to_vec_pretty(&report)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn xml_escape_special_characters() {
+        assert_eq!(xml_escape("a < b && c > d"), "a &lt; b &amp;&amp; c &gt; d");
+        assert_eq!(xml_escape(r#""quoted" 'text'"#), "&quot;quoted&quot; &apos;text&apos;");
+    }
+
+    #[test]
+    fn xml_escape_control_characters() {
+        assert_eq!(xml_escape("a\u{1b}[31mred\u{1b}[0m\0"), "a[31mred[0m");
+        assert_eq!(xml_escape("line\r\n\tindented"), "line\r\n\tindented");
+    }
+
+    #[test]
+    fn xml_escape_plain_text() {
+        assert_eq!(xml_escape(""), "");
+        assert_eq!(xml_escape("build/main.o: ünïcode"), "build/main.o: ünïcode");
+    }
+}
@@ -0,0 +1,158 @@
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// State directory, relative to the root directory
+const STATE_DIR: &str = "target/.factory";
+
+/// State recorded after the last successful run of a step
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StepState {
+    /// Resolved command line and environment
+    pub fingerprint: Fingerprint,
+    /// Hashes of input files, only recorded in hash freshness mode
+    pub inputs: BTreeMap<PathBuf, String>,
+    /// Hashes of output files, only recorded in hash freshness mode
+    pub outputs: BTreeMap<PathBuf, String>,
+    /// Additional inputs read from the depfile of the command
+    #[serde(default)]
+    pub discovered: Vec<PathBuf>,
+}
+
+/// Everything that affects how a command is executed, besides its inputs
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Fingerprint {
+    /// Hash of the command line
+    pub argv: String,
+    /// Working directory
+    pub cwd: PathBuf,
+    /// Hashes of environment variable values, by name
+    pub env: BTreeMap<String, String>,
+}
+impl Fingerprint {
+    pub fn new(argv: &[String], cwd: &Path, env: &HashMap<String, String>) -> Self {
+        Self {
+            argv: hash_bytes(argv.join("\0").as_bytes()),
+            cwd: cwd.to_owned(),
+            env: env.iter().map(|(k, v)| (k.clone(), hash_bytes(v.as_bytes()))).collect(),
+        }
+    }
+
+    /// Human-readable descriptions of differences to an earlier fingerprint
+    pub fn changes_since(&self, previous: &Self) -> Vec<String> {
+        let mut changes = Vec::new();
+        if self.argv != previous.argv {
+            changes.push("command line changed".to_owned());
+        }
+        if self.cwd != previous.cwd {
+            changes.push(format!(
+                "working directory changed from {:?} to {:?}",
+                previous.cwd, self.cwd
+            ));
+        }
+        for (key, value) in &self.env {
+            match previous.env.get(key) {
+                None => changes.push(format!("environment variable {} added", key)),
+                Some(old) if old != value => changes.push(format!("environment variable {} changed", key)),
+                Some(_) => {},
+            }
+        }
+        for key in previous.env.keys() {
+            if !self.env.contains_key(key) {
+                changes.push(format!("environment variable {} removed", key));
+            }
+        }
+        changes
+    }
+}
+
+/// Persistent per-project state database.
+/// Steps are keyed by their output paths, as step ids are not stable between runs.
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct StateDb {
+    steps: HashMap<String, StepState>,
+    /// Duration of the last execution of each step in seconds, keyed like `steps`
+    durations: HashMap<String, f64>,
+}
+impl StateDb {
+    pub fn path(root_dir: &Path) -> PathBuf {
+        root_dir.join(STATE_DIR).join("state.json")
+    }
+
+    /// Load the database, or start from scratch if it's missing or unreadable
+    pub fn load(root_dir: &Path) -> Self {
+        let path = Self::path(root_dir);
+        match fs::read(&path) {
+            Ok(contents) => serde_json::from_slice(&contents).unwrap_or_else(|e| {
+                log::warn!("Ignoring invalid state file {:?}: {}", path, e);
+                Self::default()
+            }),
+            Err(_) => Self::default(),
+        }
+    }
+
+    pub fn save(&self, root_dir: &Path) -> io::Result<()> {
+        let path = Self::path(root_dir);
+        fs::create_dir_all(path.parent().unwrap())?;
+        fs::write(path, serde_json::to_vec(self)?)
+    }
+
+    pub fn get(&self, key: &str) -> Option<&StepState> {
+        self.steps.get(key)
+    }
+
+    pub fn insert(&mut self, key: String, state: StepState) {
+        self.steps.insert(key, state);
+    }
+
+    pub fn duration(&self, key: &str) -> Option<Duration> {
+        self.durations.get(key).map(|secs| Duration::from_secs_f64(*secs))
+    }
+
+    pub fn record_duration(&mut self, key: String, duration: Duration) {
+        self.durations.insert(key, duration.as_secs_f64());
+    }
+}
+
+/// Hex-encoded SHA-256 digest of the data
+pub fn hash_bytes(data: &[u8]) -> String {
+    hex(&Sha256::digest(data))
+}
+
+/// Hex-encoded SHA-256 digest of file contents
+pub fn hash_file(p: &Path) -> io::Result<String> {
+    let mut file = fs::File::open(p)?;
+    let mut hasher = Sha256::new();
+    let mut buffer = [0u8; 0x4000];
+    loop {
+        let count = file.read(&mut buffer)?;
+        if count == 0 {
+            break;
+        }
+        hasher.input(&buffer[..count]);
+    }
+    Ok(hex(&hasher.result()))
+}
+
+/// Hash all files in the path, recursing into directories.
+/// Unreadable and nonexistent paths are left out of the result.
+pub fn hash_tree(p: &Path, result: &mut BTreeMap<PathBuf, String>) {
+    if p.is_dir() {
+        if let Ok(entries) = fs::read_dir(p) {
+            for entry in entries.flatten() {
+                hash_tree(&entry.path(), result);
+            }
+        }
+    } else if let Ok(hash) = hash_file(p) {
+        result.insert(p.to_owned(), hash);
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
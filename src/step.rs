@@ -1,8 +1,9 @@
 use pyo3::{prelude::*, types::*};
+use serde::Serialize;
 use std::collections::HashSet;
 use std::fmt;
 
-#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize)]
 pub struct StepId(u64);
 impl StepId {
     pub fn first() -> Self {
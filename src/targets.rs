@@ -0,0 +1,142 @@
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+
+use super::command::is_glob;
+use super::depgraph::IdGraph;
+use super::step::{Step, StepId};
+use super::{find_target_id, RunError};
+
+/// Summary of a build target
+#[derive(Debug)]
+pub struct TargetInfo {
+    pub name: String,
+    pub note: Option<String>,
+    /// Targets this one depends on, not counting indirect dependencies
+    pub requires: Vec<String>,
+    /// Names of all steps that would be executed, in id order
+    pub steps: Vec<String>,
+}
+impl TargetInfo {
+    /// Print a single line summary, and the note if any
+    pub fn show_short(&self) {
+        print!("{} ({} steps)", self.name, self.steps.len());
+        if !self.requires.is_empty() {
+            print!(", requires {}", self.requires.join(", "));
+        }
+        println!();
+        if let Some(note) = &self.note {
+            println!("    {}", note);
+        }
+    }
+
+    /// Print a full description, including all steps
+    pub fn show(&self) {
+        println!("Target: {}", self.name);
+        if let Some(note) = &self.note {
+            println!("Note: {}", note);
+        }
+        if self.requires.is_empty() {
+            println!("Requires: (nothing)");
+        } else {
+            println!("Requires: {}", self.requires.join(", "));
+        }
+        println!("Steps ({}):", self.steps.len());
+        for step in &self.steps {
+            println!("    {}", step);
+        }
+    }
+}
+
+/// All available targets, sorted by name
+pub fn list(steps: &[Step]) -> Vec<TargetInfo> {
+    let mut names: Vec<&String> = steps.iter().filter_map(|s| s.target_name.as_ref()).collect();
+    names.sort();
+    names.dedup();
+    names.into_iter().map(|name| describe(steps, name).unwrap()).collect()
+}
+
+pub fn describe(steps: &[Step], target_name: &str) -> Result<TargetInfo, RunError> {
+    let graph = IdGraph::from_steps(steps);
+    let target = find_target_id(steps, target_name)?;
+    let required = graph.focus(&[target]).nodes();
+
+    // Other targets in the subgraph, by their canonical step
+    let mut required_targets: Vec<(StepId, &String)> = Vec::new();
+    for step in steps {
+        if let Some(name) = &step.target_name {
+            if name != target_name
+                && required.contains(&step.id)
+                && find_target_id(steps, name)? == step.id
+            {
+                required_targets.push((step.id, name));
+            }
+        }
+    }
+
+    // Remove targets that are required by another required target
+    let indirect: HashSet<StepId> = required_targets
+        .iter()
+        .flat_map(|(id, _)| {
+            let mut ancestors = graph.focus(&[*id]).nodes();
+            ancestors.remove(id);
+            ancestors
+        })
+        .collect();
+    let mut requires: Vec<String> = required_targets
+        .into_iter()
+        .filter(|(id, _)| !indirect.contains(id))
+        .map(|(_, name)| name.clone())
+        .collect();
+    requires.sort();
+
+    let step_by_id = |id: StepId| steps.iter().find(|s| s.id == id).unwrap();
+    let mut step_ids: Vec<StepId> =
+        required.into_iter().filter(|id| step_by_id(*id).py_obj.is_some()).collect();
+    step_ids.sort();
+
+    Ok(TargetInfo {
+        name: target_name.to_owned(),
+        note: step_by_id(target).note(),
+        requires,
+        steps: step_ids.into_iter().map(|id| step_by_id(id).name.clone()).collect(),
+    })
+}
+
+/// Targets that require a step with one of the changed paths as an input.
+/// A path changes an input if it's the input itself, inside an input directory,
+/// or matches an input glob.
+pub fn affected(
+    steps: &[Step], target_names: &[String], inputs: &HashMap<StepId, Vec<PathBuf>>,
+    changed: &HashSet<PathBuf>,
+) -> Vec<String>
+{
+    let graph = IdGraph::from_steps(steps);
+    target_names
+        .iter()
+        .filter(|name| {
+            let id = if let Ok(id) = find_target_id(steps, name) {
+                id
+            } else {
+                return false;
+            };
+            graph.focus(&[id]).nodes().iter().any(|step| {
+                inputs
+                    .get(step)
+                    .map(|paths| paths.iter().any(|p| changed.iter().any(|c| input_changed(p, c))))
+                    .unwrap_or(false)
+            })
+        })
+        .cloned()
+        .collect()
+}
+
+/// Does a change to the path affect the input
+fn input_changed(input: &Path, changed: &Path) -> bool {
+    if is_glob(input) {
+        glob::Pattern::new(&input.to_string_lossy())
+            .map(|pattern| pattern.matches_path(changed))
+            .unwrap_or(false)
+    } else {
+        changed.starts_with(input)
+    }
+}
@@ -0,0 +1,216 @@
+use std::collections::{HashMap, HashSet};
+use std::ffi::{CString, OsStr};
+use std::fs;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::cancel;
+use crate::command::is_glob;
+
+/// Wait this long after an event for more events, before reporting changes
+pub const DEBOUNCE: Duration = Duration::from_millis(200);
+
+/// How often to check for interruption while waiting
+const POLL_INTERVAL_MS: libc::c_int = 100;
+
+/// Events on watched files themselves
+const FILE_MASK: u32 =
+    libc::IN_MODIFY | libc::IN_CLOSE_WRITE | libc::IN_ATTRIB | libc::IN_DELETE_SELF | libc::IN_MOVE_SELF;
+
+/// Events on directories containing watched files, to notice replaced files
+const DIR_MASK: u32 = libc::IN_CREATE | libc::IN_MOVED_TO | libc::IN_DELETE | libc::IN_CLOSE_WRITE;
+
+/// Watches files for changes using inotify
+pub struct Watcher {
+    fd: libc::c_int,
+    /// Watched path by watch descriptor
+    watches: HashMap<libc::c_int, PathBuf>,
+    /// Paths to report changes in
+    paths: HashSet<PathBuf>,
+}
+impl Watcher {
+    pub fn new() -> io::Result<Self> {
+        let fd = unsafe { libc::inotify_init1(libc::IN_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Self {
+            fd,
+            watches: HashMap::new(),
+            paths: HashSet::new(),
+        })
+    }
+
+    /// Number of paths changes are reported for
+    pub fn count(&self) -> usize {
+        self.paths.len()
+    }
+
+    /// Watch a file or a directory tree, and the directory containing it.
+    /// Paths that don't exist are watched through their parent directory only,
+    /// and glob patterns through the directory before the first wildcard.
+    pub fn add(&mut self, path: &Path) -> io::Result<()> {
+        let path = &glob_base(path);
+        if !self.paths.insert(path.to_owned()) {
+            return Ok(());
+        }
+        if path.is_dir() {
+            self.add_tree(path)?;
+        } else if path.exists() {
+            self.add_watch(path, FILE_MASK)?;
+        }
+        if let Some(parent) = path.parent().filter(|p| p.is_dir()) {
+            self.add_watch(parent, DIR_MASK)?;
+        }
+        Ok(())
+    }
+
+    /// Watch a directory and all directories below it, as inotify watches are not recursive
+    fn add_tree(&mut self, dir: &Path) -> io::Result<()> {
+        self.add_watch(dir, FILE_MASK | DIR_MASK)?;
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            // Symlinks are not followed, to avoid loops
+            if entry.file_type()?.is_dir() {
+                self.add_tree(&entry.path())?;
+            }
+        }
+        Ok(())
+    }
+
+    fn add_watch(&mut self, path: &Path, mask: u32) -> io::Result<()> {
+        let c_path = CString::new(path.as_os_str().as_bytes())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        let wd = unsafe { libc::inotify_add_watch(self.fd, c_path.as_ptr(), mask) };
+        if wd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        self.watches.entry(wd).or_insert_with(|| path.to_owned());
+        Ok(())
+    }
+
+    /// Block until some of the watched paths change.
+    /// Returns the changed paths, which may be inside watched directories, or `None` if interrupted.
+    pub fn wait(&mut self) -> io::Result<Option<HashSet<PathBuf>>> {
+        let mut changed = HashSet::new();
+        loop {
+            let timeout = if changed.is_empty() {
+                POLL_INTERVAL_MS
+            } else {
+                DEBOUNCE.as_millis() as libc::c_int
+            };
+            if cancel::interrupted() {
+                return Ok(None);
+            }
+            if self.poll(timeout)? {
+                self.read_events(&mut changed)?;
+            } else if !changed.is_empty() {
+                return Ok(Some(changed));
+            }
+        }
+    }
+
+    /// Changes to watched paths since the last call, without blocking
+    pub fn take_pending(&mut self) -> io::Result<HashSet<PathBuf>> {
+        let mut changed = HashSet::new();
+        while self.poll(0)? {
+            self.read_events(&mut changed)?;
+        }
+        Ok(changed)
+    }
+
+    /// Wait until events are available, or the timeout expires
+    fn poll(&self, timeout_ms: libc::c_int) -> io::Result<bool> {
+        let mut pollfd = libc::pollfd {
+            fd: self.fd,
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        let count = unsafe { libc::poll(&mut pollfd, 1, timeout_ms) };
+        if count < 0 {
+            let err = io::Error::last_os_error();
+            return if err.kind() == io::ErrorKind::Interrupted {
+                Ok(false)
+            } else {
+                Err(err)
+            };
+        }
+        Ok(count > 0)
+    }
+
+    /// Read available events, adding changed watched paths to `changed`
+    fn read_events(&mut self, changed: &mut HashSet<PathBuf>) -> io::Result<()> {
+        let mut buffer = vec![0u8; 0x1000];
+        let count = unsafe { libc::read(self.fd, buffer.as_mut_ptr() as *mut libc::c_void, buffer.len()) };
+        if count < 0 {
+            let err = io::Error::last_os_error();
+            return if err.kind() == io::ErrorKind::Interrupted {
+                Ok(())
+            } else {
+                Err(err)
+            };
+        }
+
+        let header_size = std::mem::size_of::<libc::inotify_event>();
+        let mut offset = 0;
+        while offset + header_size <= count as usize {
+            let event: libc::inotify_event =
+                unsafe { std::ptr::read_unaligned(buffer[offset..].as_ptr() as *const libc::inotify_event) };
+            let name_start = offset + header_size;
+            let name_end = name_start + event.len as usize;
+            offset = name_end;
+
+            let dir = if let Some(dir) = self.watches.get(&event.wd) {
+                dir
+            } else {
+                continue;
+            };
+            let path = if event.len > 0 {
+                // The name is padded with null bytes
+                let name = &buffer[name_start..name_end];
+                let len = name.iter().position(|b| *b == 0).unwrap_or(name.len());
+                dir.join(OsStr::from_bytes(&name[..len]))
+            } else {
+                dir.clone()
+            };
+            log::debug!("Watch event {:#x} on {:?}", event.mask, path);
+
+            // Events on siblings of watched files are ignored
+            if !self.paths.iter().any(|p| path.starts_with(p)) {
+                continue;
+            }
+
+            // New directories inside a watched tree must be watched too
+            let created = libc::IN_CREATE | libc::IN_MOVED_TO;
+            if event.mask & libc::IN_ISDIR != 0 && event.mask & created != 0 {
+                if let Err(e) = self.add_tree(&path) {
+                    log::debug!("Unable to watch {:?}: {}", path, e);
+                }
+            }
+            changed.insert(path);
+        }
+        Ok(())
+    }
+}
+impl Drop for Watcher {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.fd);
+        }
+    }
+}
+
+/// Directory before the first component with wildcards, or the path itself if it has none
+fn glob_base(path: &Path) -> PathBuf {
+    if !is_glob(path) {
+        return path.to_owned();
+    }
+    let base: PathBuf = path.components().take_while(|c| !is_glob(Path::new(c))).collect();
+    if base.as_os_str().is_empty() {
+        PathBuf::from(".")
+    } else {
+        base
+    }
+}